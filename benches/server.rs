@@ -0,0 +1,138 @@
+//! Benchmarks of a server started from the built binary
+//!
+//! `cargo bench --features tokio -- [connections] [notifications]`
+//!
+//! Connects idle consumers to see what they cost the server,
+//! then measures how long notifications take to reach all of them.
+//! Both processes need a file descriptor for every connection, see `ulimit -n`.
+
+use std::net::TcpListener;
+use std::process::Child;
+use std::process::Command;
+use std::process::Stdio;
+use std::time::Duration;
+use std::time::Instant;
+
+use notificationd::client::Event;
+use notificationd::client::tokio::Client;
+use notificationd::notifications::NotificationDetails;
+use tokio::sync::mpsc;
+
+const USER: &str = "bench";
+
+struct Server {
+    process: Child,
+    address: String,
+    database: std::path::PathBuf,
+}
+
+impl Server {
+    fn start() -> Self {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let address = format!("127.0.0.1:{port}");
+        let database = std::env::temp_dir().join(format!("notificationd-bench-{}.sqlite3", std::process::id()));
+        let process = Command::new(env!("CARGO_BIN_EXE_notificationd"))
+            .args(["--config", "/dev/null", "--bind", &address, "--database"])
+            .arg(&database)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start the server");
+        Self { process, address, database }
+    }
+
+    /// A field of `/proc/<pid>/status`
+    fn status(&self, field: &str) -> String {
+        let status = std::fs::read_to_string(format!("/proc/{}/status", self.process.id())).unwrap_or_default();
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(field)?.strip_prefix(':'))
+            .map_or(String::from("?"), |value| value.trim().to_owned())
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = std::fs::remove_file(&self.database);
+    }
+}
+
+async fn login(address: &str) -> (Client, notificationd::client::tokio::Events) {
+    for _ in 0..100 {
+        if let Ok((client, events)) = Client::connect(address).await {
+            client.login(USER, None).await.expect("login failed");
+            return (client, events);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("server did not accept connections");
+}
+
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    sorted[(sorted.len() - 1) * p / 100]
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    // cargo passes --bench
+    let mut args = std::env::args().skip(1).filter(|arg| !arg.starts_with("--"));
+    let connections: usize = args.next().map_or(1000, |n| n.parse().expect("connections"));
+    let notifications: usize = args.next().map_or(100, |n| n.parse().expect("notifications"));
+
+    let server = Server::start();
+    let (sender, _) = login(&server.address).await;
+    println!("idle server: {} threads, {} resident", server.status("Threads"), server.status("VmRSS"));
+
+    let start = Instant::now();
+    let (received_tx, mut received) = mpsc::unbounded_channel();
+    let mut consumers = vec![];
+    for _ in 0..connections {
+        let (client, mut events) = login(&server.address).await;
+        client.consume().await.unwrap();
+        let received_tx = received_tx.clone();
+        tokio::spawn(async move {
+            while let Some(Ok(event)) = events.recv().await {
+                if let Event::Notification(details) = event {
+                    let _ = received_tx.send((details.id, Instant::now()));
+                }
+            }
+        });
+        consumers.push(client);
+    }
+    println!(
+        "{connections} consumers connected in {:.2?}: {} threads, {} resident",
+        start.elapsed(),
+        server.status("Threads"),
+        server.status("VmRSS"),
+    );
+
+    let mut details = NotificationDetails::new();
+    details.title = Some(String::from("Benchmark"));
+    details.body = Some(String::from("Relayed to every consumer"));
+    let (mut replies, mut fan_outs) = (vec![], vec![]);
+    for _ in 0..notifications {
+        let start = Instant::now();
+        let sent = sender.send(&details).await.unwrap();
+        replies.push(start.elapsed());
+        assert_eq!(sent.delivered, connections, "not relayed to every consumer");
+        let mut last = start;
+        for _ in 0..connections {
+            let (id, at) = received.recv().await.unwrap();
+            assert_eq!(id, sent.id, "notifications out of order");
+            last = last.max(at);
+        }
+        fan_outs.push(last - start);
+    }
+    replies.sort();
+    fan_outs.sort();
+    for (name, times) in [("SEND reply", &replies), ("fan-out", &fan_outs)] {
+        println!(
+            "{name} to {connections} consumers: median {:.2?}, p99 {:.2?}, max {:.2?}",
+            percentile(times, 50),
+            percentile(times, 99),
+            times[times.len() - 1],
+        );
+    }
+}
@@ -1,7 +1,18 @@
+use std::io::BufRead;
+use std::path::Path;
+
 use anyhow::Context;
+use argon2::Argon2;
+use argon2::PasswordHasher;
+use argon2::password_hash::SaltString;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::rand_core::RngCore;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
 use clap::Parser;
 use notificationd::levitating_notificationd;
 use notificationd::levitating_notificationd::VarlinkClientInterface;
+use notificationd::notifications::Urgency;
 use varlink::Connection;
 
 #[derive(clap::Subcommand)]
@@ -9,6 +20,57 @@ enum Command {
     Status,
 	// Show connected clients
 	Who,
+    /// Send a notification, through the client daemon of the user if it runs
+    Send {
+        title: String,
+        body: Option<String>,
+        /// Icon name, or a path to a PNG file to send inline
+        #[arg(short, long)]
+        icon: Option<String>,
+        /// Tag the notification, may be repeated
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+        /// Send to these users, user@host logins or @groups instead of yourself
+        #[arg(long)]
+        to: Vec<String>,
+        /// Add an action as KEY=LABEL or just a label, may be repeated
+        #[arg(short = 'A', long = "action")]
+        actions: Vec<String>,
+        /// URL the server posts invoked actions to
+        #[arg(long)]
+        callback: Option<String>,
+        /// low, normal or critical, critical notifications stay on screen
+        #[arg(short, long, value_parser = parse_urgency)]
+        urgency: Option<levitating_notificationd::Urgency>,
+        /// Only store the notification in the history
+        #[arg(short, long)]
+        quiet: bool,
+        /// Don't store the notification in the history
+        #[arg(short, long)]
+        ephemeral: bool,
+        /// Print the id of the notification
+        #[arg(short, long)]
+        print_id: bool,
+    },
+    /// Delete notifications from the history
+    Delete {
+        ids: Vec<i64>,
+    },
+    /// Delete all notifications matching every given filter
+    Purge {
+        /// Sent by this user or its user@host logins
+        #[arg(long)]
+        user: Option<String>,
+        #[arg(long)]
+        tag: Option<String>,
+        /// Older than this age, like 30d
+        #[arg(long, value_parser = parse_age)]
+        older_than: Option<i64>,
+    },
+    /// Hash a password read from stdin for the credentials file
+    Hash,
+    /// Generate an API token and its hash for the credentials file
+    Token,
 }
 
 #[derive(Parser)]
@@ -17,6 +79,9 @@ struct Cli {
     command: Command,
     #[arg(long)]
     user: bool,
+    /// Varlink address of the daemon, instead of the system server or that of the user
+    #[arg(long)]
+    address: Option<String>,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -26,7 +91,7 @@ fn main() -> anyhow::Result<()> {
     } else {
         nix::unistd::Uid::from_raw(0)
     };
-    let addr = levitating_notificationd::address(uid);
+    let addr = cli.address.clone().unwrap_or_else(|| levitating_notificationd::address(uid));
     match cli.command {
         Command::Status => {
             let mut client = connect(&addr)?;
@@ -37,12 +102,29 @@ fn main() -> anyhow::Result<()> {
                 println!("Connections: {}", server.connections);
                 println!("Persistent: {}", server.persistent);
                 println!("Bind: {}", server.bind);
+                println!("Queue size: {} ({})", server.queue_size, server.slow_consumers);
+                println!("Dropped: {}", server.dropped);
+                println!("Disconnected: {}", server.disconnected);
+                for queue in server.queues {
+                    let login = queue.login.unwrap_or(String::from("-"));
+                    println!("{login} {} queued {} dropped {}", queue.address, queue.queued, queue.dropped);
+                }
             }
-            if let Some(client) = status.client {
+            if let Some(clients) = status.clients {
                 println!("Mode: client");
-                println!("Server: {}", client.server);
-                println!("Login: {}", client.login);
-                println!("Consume: {}", client.consume);
+                for client in clients {
+                    println!();
+                    println!("Server: {}", client.server);
+                    println!("Login: {}", client.login);
+                    if client.connected {
+                        println!("State: connected for {}", format_duration(client.uptime));
+                    } else {
+                        println!("State: disconnected");
+                    }
+                    println!("Consume: {}", client.consume);
+                    println!("Last id: {}", client.last_id.map_or(String::from("none"), |id| id.to_string()));
+                    println!("Reconnects: {}", client.reconnects);
+                }
             }
         },
         Command::Who => {
@@ -50,13 +132,119 @@ fn main() -> anyhow::Result<()> {
             let who = client.who().call()?;
             println!("Connected clients:");
             for c in who.clients {
-                println!("{} {} {}", c.login, if c.consume { "CONSUME" } else { &" ".repeat("CONSUME".len()) }, c.address);
+                println!("{} {} {}", c.login, if c.consume { "CONSUME" } else { "       " }, c.address);
+            }
+        },
+        Command::Send { title, body, icon, tags, to, actions, callback, urgency, quiet, ephemeral, print_id } => {
+            let mut client = if cli.user || cli.address.is_some() {
+                connect(&addr)?
+            } else {
+                let user_addr = levitating_notificationd::address(nix::unistd::getuid());
+                connect(&user_addr).or_else(|_| connect(&addr))?
+            };
+            let (icon, icon_png) = match icon {
+                Some(path) if path.to_lowercase().ends_with(".png") && Path::new(&path).is_file() => {
+                    let data = std::fs::read(&path).context(format!("failed to read {path}"))?;
+                    (None, Some(BASE64.encode(data)))
+                },
+                icon => (icon, None),
+            };
+            let actions = actions
+                .into_iter()
+                .enumerate()
+                .map(|(i, action)| match action.split_once('=') {
+                    Some((key, label)) => levitating_notificationd::Action {
+                        key: key.to_owned(),
+                        label: label.to_owned(),
+                    },
+                    None => levitating_notificationd::Action {
+                        key: i.to_string(),
+                        label: action,
+                    },
+                })
+                .collect();
+            let sent = client
+                .send(
+                    Some(title),
+                    body,
+                    icon,
+                    icon_png,
+                    Some(tags),
+                    Some(to),
+                    urgency,
+                    Some(actions),
+                    callback,
+                    Some(quiet),
+                    Some(ephemeral),
+                )
+                .call()?;
+            if print_id {
+                println!("{}", sent.id);
             }
         },
+        Command::Delete { ids } => {
+            let mut client = connect(&addr)?;
+            for id in ids {
+                client.delete(id).call().context(format!("failed deleting {id}"))?;
+                println!("Deleted {id}");
+            }
+        },
+        Command::Purge { user, tag, older_than } => {
+            if user.is_none() && tag.is_none() && older_than.is_none() {
+                anyhow::bail!("refusing to purge without a filter");
+            }
+            let mut client = connect(&addr)?;
+            let purged = client.purge(user, tag, older_than).call()?;
+            println!("Deleted {} notifications", purged.deleted);
+        },
+        Command::Hash => {
+            let mut password = String::new();
+            std::io::stdin().lock().read_line(&mut password)?;
+            let password = password.trim_end_matches(['\r', '\n']);
+            println!("{}", hash(password)?);
+        },
+        Command::Token => {
+            let mut bytes = [0u8; 24];
+            OsRng.fill_bytes(&mut bytes);
+            let token: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+            println!("Token: {token}");
+            println!("Hash: {}", hash(&token)?);
+        },
     }
     Ok(())
 }
 
+fn parse_age(age: &str) -> Result<i64, String> {
+    notificationd::duration::parse(age)
+        .and_then(|s| i64::try_from(s).ok())
+        .ok_or(format!("invalid age {age}, expected a number with an optional s, m, h, d or w suffix"))
+}
+
+fn parse_urgency(urgency: &str) -> Result<levitating_notificationd::Urgency, String> {
+    match urgency.parse::<Urgency>()? {
+        Urgency::Low => Ok(levitating_notificationd::Urgency::low),
+        Urgency::Normal => Ok(levitating_notificationd::Urgency::normal),
+        Urgency::Critical => Ok(levitating_notificationd::Urgency::critical),
+    }
+}
+
+fn format_duration(seconds: i64) -> String {
+    match seconds {
+        s if s < 60 => format!("{s}s"),
+        s if s < 60 * 60 => format!("{}m {}s", s / 60, s % 60),
+        s if s < 24 * 60 * 60 => format!("{}h {}m", s / 3600, s % 3600 / 60),
+        s => format!("{}d {}h", s / 86400, s % 86400 / 3600),
+    }
+}
+
+fn hash(secret: &str) -> anyhow::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(secret.as_bytes(), &salt)
+        .map_err(|e| anyhow::anyhow!("failed hashing: {e}"))?;
+    Ok(hash.to_string())
+}
+
 fn connect(addr: &str) -> anyhow::Result<levitating_notificationd::VarlinkClient> {
     let conn = Connection::with_address(addr).context(format!("failed connecting to {addr}"))?;
     Ok(levitating_notificationd::VarlinkClient::new(conn))
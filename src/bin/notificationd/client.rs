@@ -1,109 +1,344 @@
 //! code for the client daemon
 
 use anyhow::Context;
-use nix;
-use tracing::{error, warn, info, debug, trace};
+use tracing::{warn, info, debug};
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::io::BufRead;
 use std::io::BufReader;
 use std::io::Write;
-use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Instant;
+use sha2::Digest;
+use sha2::Sha256;
 use zbus::blocking::Connection;
+use zbus::zvariant::Value;
+use notificationd::notifications::Icon;
 use notificationd::notifications::NotificationDetails;
-#[cfg(target_os = "linux")]
-use libsystemd as systemd;
+use notificationd::notifications::Urgency;
+use notificationd::protocol;
+use notificationd::protocol::Command;
+use notificationd::protocol::Reply;
+use notificationd::protocol::ServerLine;
+use notificationd::protocol::ServerMessage;
 
 use crate::client::dbus::NotificationsProxyBlocking;
-use crate::protocol;
+use crate::config::ClientConfig;
+use crate::config::DisplayConfig;
+use crate::service;
+use crate::tls;
+use crate::varlink::VarlinkClientHandles;
 
+mod backoff;
+mod capture;
 mod dbus;
+mod headless;
+mod network;
+mod shown;
+mod state;
+mod upstream;
 
-pub fn main(connect: String) -> anyhow::Result<()> {
+pub use upstream::Upstream;
+
+/// Returned when the server rejects our login, retrying won't help
+#[derive(Debug)]
+struct LoginFailed(String);
+
+impl std::fmt::Display for LoginFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "login failed: {}", self.0)
+    }
+}
+
+impl std::error::Error for LoginFailed {}
+
+/// State of a connection to a server, as reported by the varlink Status method
+pub struct ConnectionStatus {
+    pub server: String,
+    pub login: String,
+    pub consume: bool,
+    /// When the current session logged in
+    pub connected_since: Option<Instant>,
+    /// The id of the last notification received
+    pub last_id: Option<usize>,
+    /// Connection attempts after the first
+    pub reconnects: u32,
+}
+
+/// State of a connection to a server that outlives a single session
+struct Client {
+    upstream: Upstream,
+    status: Arc<Mutex<ConnectionStatus>>,
+    shown: Arc<shown::Shown>,
+    notify_iface: NotificationsProxyBlocking<'static>,
+    display: DisplayConfig,
+    last_id: state::LastId,
+    /// Ids displayed this session, as catching up may send them twice.
+    /// Cleared on reconnecting, a restarted server may hand out the same ids again.
+    displayed: HashSet<usize>,
+}
+
+pub fn main(config: ClientConfig) -> anyhow::Result<()> {
     info!("Started notificationd as client");
+    if config.servers.is_empty() {
+        anyhow::bail!("no server to connect to, pass one with --client or add it to the configuration");
+    }
     let hostname = nix::unistd::gethostname()?;
     let hostname = hostname.to_string_lossy();
     let uid = nix::unistd::getuid();
     let user = nix::unistd::User::from_uid(nix::unistd::getuid())?.map_or(uid.to_string(), |u| u.name);
 
-    let stream = TcpStream::connect(connect)?;
+    let password = config.password_file
+        .map(|path| {
+            std::fs::read_to_string(&path)
+                .context(format!("failed to read {}", path.display()))
+                .map(|s| s.trim().to_owned())
+        })
+        .transpose()?;
 
-    info!("Connected to {}", stream.peer_addr()?);
+    let tls = if config.tls.enabled() {
+        Some(tls::client_config(&config.tls)?)
+    } else {
+        None
+    };
 
-    let mut writer = stream.try_clone()?;
-    let reader = BufReader::new(stream);
+    let upstreams: Vec<Upstream> = config
+        .servers
+        .into_iter()
+        .map(|server| Upstream {
+            connect: server,
+            login: format!("{user}@{hostname}"),
+            password: password.clone(),
+            tls: tls.clone(),
+            starttls: config.tls.starttls,
+        })
+        .collect();
+
+    if let Some(bus) = config.headless {
+        return headless::main(bus, upstreams[0].clone());
+    }
 
     let dbus_session = Connection::session().context("failed to connect to dbus")?;
     let notify_iface = dbus::NotificationsProxyBlocking::new(&dbus_session)?;
 
-    writer.write_all(format!("login {user}@{hostname}\r\nconsume\r\n").as_bytes())?;
-
-    let mut login_confirmation = false;
-
-    let mut details = None;
-
-    for line in reader.lines() {
-        let line = line?;
-        debug!("received {}", line);
-        let (_, msg) = protocol::parser::line(&line, false).unwrap();
-        let cmd = msg.command.to_uppercase();
-        match cmd.as_ref() {
-            "NOTIFY_START" => {
-                details = Some(NotificationDetails::new());
-                if let Some(ref mut details) = details {
-                    details.user = msg.arguments.first().cloned();
-                    details.id = msg
-                        .arguments
-                        .get(1)
-                        .cloned()
-                        .map(|s| s.parse::<usize>().unwrap());
-                }
+    let mut forward_to = None;
+    let mut statuses = vec![];
+    let (result_tx, result_rx) = mpsc::channel();
+    let mut network_txs = vec![];
+    for upstream in upstreams {
+        let (network_tx, network_rx) = mpsc::channel();
+        network_txs.push(network_tx);
+        let last_id = state::LastId::load(&upstream.connect);
+        let status = Arc::new(Mutex::new(ConnectionStatus {
+            server: upstream.connect.clone(),
+            login: upstream.login.clone(),
+            consume: false,
+            connected_since: None,
+            last_id: last_id.id,
+            reconnects: 0,
+        }));
+        statuses.push(status.clone());
+        let shown = Arc::new(shown::Shown::default());
+        shown::listen(notify_iface.clone(), shown.clone());
+        let client = Client {
+            last_id,
+            status,
+            shown,
+            upstream,
+            notify_iface: notify_iface.clone(),
+            display: config.display.clone(),
+            displayed: HashSet::new(),
+        };
+        forward_to.get_or_insert_with(|| client.upstream.clone());
+        let result_tx = result_tx.clone();
+        std::thread::Builder::new()
+            .name(client.upstream.connect.clone())
+            .spawn(move || {
+                let _ = result_tx.send(client.run(network_rx));
+            })?;
+    }
+    network::watch(network_txs);
+
+    if let Some(upstream) = &forward_to
+        && config.capture
+    {
+        capture::start(upstream.clone())?;
+    }
+
+    if let Some(upstream) = forward_to {
+        crate::varlink::init(None, Some(VarlinkClientHandles { upstream, connections: statuses }), None)?;
+    }
+
+    // we are ready to display notifications, connection state is reported in the status
+    service::watchdog(|| true);
+    service::notify(true, "Connecting");
+
+    // connections are retried forever, so any result is fatal
+    result_rx.recv()?
+}
+
+impl Client {
+    /// Keep a session with the server, reconnecting when it is lost
+    fn run(mut self, network_changes: mpsc::Receiver<()>) -> anyhow::Result<()> {
+        let mut backoff = backoff::Backoff::new();
+        loop {
+            let mut logged_in = false;
+            let res = self.session(&mut logged_in);
+            self.shown.set_writer(None);
+            {
+                let mut status = self.status.lock().unwrap();
+                status.connected_since = None;
+                status.consume = false;
+            }
+            match res {
+                Ok(()) => warn!("Connection to {} closed", self.upstream.connect),
+                Err(e) if e.is::<LoginFailed>() => return Err(e.context(self.upstream.connect)),
+                Err(e) => warn!("Connection to {} failed: {e:#}", self.upstream.connect),
             }
-            "TITLE" => {
-                if let Some(ref mut details) = details {
-                    details.title = msg.trailing;
+            if logged_in {
+                backoff.reset();
+            }
+            let delay = backoff.next_delay();
+            info!("Reconnecting in {:.1}s", delay.as_secs_f32());
+            service::notify(false, &format!("Reconnecting to {} in {}s", self.upstream.connect, delay.as_secs()));
+            match network_changes.recv_timeout(delay) {
+                Ok(()) => {
+                    info!("Network changed, reconnecting now");
+                    backoff.reset();
                 }
+                Err(RecvTimeoutError::Timeout) => {}
+                // not watching the network
+                Err(RecvTimeoutError::Disconnected) => std::thread::sleep(delay),
             }
-            "BODY" => {
-                if let Some(ref mut details) = details {
-                    if let Some(body) = &mut details.body {
-                        body.push_str(&msg.trailing.unwrap());
-                        body.push('\n');
-                    } else {
-                        details.body = msg.trailing.map(|mut s| {
-                            s.push('\n');
-                            s
-                        });
+            // drop changes that happened while waiting
+            while network_changes.try_recv().is_ok() {}
+            self.status.lock().unwrap().reconnects += 1;
+        }
+    }
+
+    /// Connect, login and display notifications until the connection is lost
+    fn session(&mut self, logged_in: &mut bool) -> anyhow::Result<()> {
+        let stream = self.upstream.open_stream()?;
+        let mut writer = stream.try_clone()?;
+        let reader = BufReader::new(stream);
+        self.displayed.clear();
+        self.last_id.new_session();
+
+        writer.write_all(self.upstream.login_message().as_bytes())?;
+        writer.write_all(Command::Consume(true).line(None).as_bytes())?;
+        self.shown.set_writer(Some(writer.try_clone()?));
+
+        // catching up after consume means nothing is missed in between,
+        // but notifications may arrive twice
+        if let Some(id) = self.last_id.id {
+            writer.write_all(Command::Since(id.try_into()?).line(None).as_bytes())?;
+        }
+
+        let mut details = None;
+
+        for line in reader.lines() {
+            let line = line?;
+            debug!("received {}", line);
+            let Some(line) = ServerLine::parse(&line) else {
+                continue;
+            };
+            let msg = match line {
+                ServerLine::Message(msg) => msg,
+                ServerLine::Reply(reply) => {
+                    self.handle_reply(reply, logged_in, &writer)?;
+                    continue;
+                }
+            };
+            match msg {
+                ServerMessage::NotifyStart { user, id, timestamp } => {
+                    let mut start = NotificationDetails::new();
+                    start.user = Some(user);
+                    start.id = Some(id);
+                    start.timestamp = timestamp;
+                    details = Some(start);
+                }
+                ServerMessage::Invoked { id, key, login } => {
+                    // our own notification, probably sent with notificationctl
+                    info!("{login} invoked {key} on notification {id}");
+                }
+                ServerMessage::Dismiss(id) => {
+                    if let Some(dbus_id) = self.shown.dismissed(id) {
+                        debug!("closing notification {id} dismissed elsewhere");
+                        if let Err(e) = self.notify_iface.close_notification(dbus_id) {
+                            warn!("failed to close notification: {e}");
+                        }
                     }
                 }
-            }
-            "NOTIFY_END" => {
-                if let Some(details) = details {
-                    let _ = display(details, &notify_iface)?;
+                ServerMessage::Notice(text) => info!("{}: {text}", self.upstream.connect),
+                ServerMessage::NotifyEnd(_) => {
+                    if let Some(details) = details.take() {
+                        self.received(details)?;
+                    }
                 }
-                details = None;
-            },
-            "LOGIN" => {
-                if msg.sign == Some('+') && !login_confirmation {
-                    login_confirmation = true;
-                    // notify systemd of readiness
-                    #[cfg(target_os = "linux")]
-                    if systemd::daemon::booted() {
-                        use systemd::daemon::NotifyState;
-                        let status = format!("Connected to {} as {}", writer.peer_addr()?, format!("{user}@{hostname}"));
-                        systemd::daemon::notify(false, &[NotifyState::Ready, NotifyState::Status(status)])?;
+                msg => {
+                    if let Some(ref mut details) = details {
+                        msg.add_to(details);
                     }
                 }
-            },
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_reply(&mut self, reply: Reply, logged_in: &mut bool, writer: &tls::Stream) -> anyhow::Result<()> {
+        match (reply.command.as_ref(), reply.result) {
+            ("DISMISS", Err(code)) => warn!("failed to dismiss notification: {code}"),
+            ("INVOKE", Err(code)) => warn!("failed to invoke action: {code}"),
+            ("SINCE", Ok(args)) => {
+                info!("Caught up on {} missed notifications", args.first().map_or("?", |n| n));
+            }
+            ("SINCE", Err(code)) => warn!("failed to catch up: {code}"),
+            ("LOGIN", Err(code)) => return Err(LoginFailed(code.to_string()).into()),
+            ("CONSUME", Ok(args)) => {
+                self.status.lock().unwrap().consume = protocol::parse_bool(args.first()).unwrap_or(false);
+            }
+            ("LOGIN", Ok(_)) if !*logged_in => {
+                *logged_in = true;
+                self.status.lock().unwrap().connected_since = Some(Instant::now());
+                service::notify(false, &format!("Connected to {} as {}", writer.peer_addr()?, self.upstream.login));
+            }
             _ => {}
         }
+        Ok(())
+    }
+
+    /// Display a relayed notification, unless it was already displayed or captured here
+    fn received(&mut self, details: NotificationDetails) -> anyhow::Result<()> {
+        match details.id {
+            Some(id) if !self.displayed.insert(id) => debug!("already displayed {id}"),
+            _ if details.user.as_ref() == Some(&self.upstream.login)
+                && details.tags.iter().any(|t| t == capture::TAG) =>
+            {
+                debug!("not displaying a notification captured here");
+            }
+            id => {
+                let dbus_id = display(details, &self.notify_iface, &self.display)?;
+                if let Some(id) = id {
+                    self.shown.shown(dbus_id, id);
+                    self.last_id.update(id).context("failed to store last id")?;
+                    self.status.lock().unwrap().last_id = self.last_id.id;
+                }
+            }
+        }
+        Ok(())
     }
-    Ok(())
 }
 
+/// Show a notification, returning its D-Bus id
 fn display(
     notification: NotificationDetails,
     iface: &NotificationsProxyBlocking,
-) -> anyhow::Result<()> {
+    config: &DisplayConfig,
+) -> anyhow::Result<u32> {
     info!(
         "Displaying notification {}: {:?}",
         notification.id.map_or(String::from("?"),
@@ -111,15 +346,63 @@ fn display(
         notification.title.clone().unwrap_or(String::from(""))
     );
     debug!("{notification:?}");
-    iface.notify(
-        &notification.user.unwrap_or(String::from("notificationd")),
+    let mut hints = HashMap::new();
+    let icon = match &notification.icon {
+        Some(Icon::Name(name)) => name.clone(),
+        Some(Icon::Png(data)) => match cache_icon(data) {
+            Ok(path) => path,
+            Err(e) => {
+                warn!("failed to cache icon: {e:#}");
+                config.default_icon.clone()
+            }
+        },
+        None => config.default_icon.clone(),
+    };
+    let image_path = Value::from(icon.as_str());
+    if matches!(notification.icon, Some(Icon::Png(_))) {
+        hints.insert("image-path", &image_path);
+    }
+    let urgency = Value::from(notification.urgency.level());
+    hints.insert("urgency", &urgency);
+    let id = Value::from(notification.id.unwrap_or(0) as u64);
+    hints.insert(capture::HINT, &id);
+    // critical notifications stay until they are dismissed
+    let timeout = match notification.urgency {
+        Urgency::Critical => 0,
+        _ => config.timeout,
+    };
+    let app_name = notification.user.filter(|_| config.show_sender);
+    // actions are passed as alternating keys and labels
+    let actions: Vec<&str> = notification
+        .actions
+        .iter()
+        .flat_map(|(key, label)| [key.as_str(), label.as_str()])
+        .collect();
+    Ok(iface.notify(
+        &app_name.unwrap_or(String::from("notificationd")),
         0,
-        "dialog-information",
+        &icon,
         &notification.title.unwrap_or(String::from("")),
         &notification.body.unwrap_or(String::from("")),
-        &[],
-        HashMap::new(),
-        0,
-    )?;
-    Ok(())
+        &actions,
+        hints,
+        timeout,
+    )?)
+}
+
+/// Write an inline icon to the cache directory, returning its path.
+/// Files are named after their hash so repeated icons are written once.
+fn cache_icon(data: &[u8]) -> anyhow::Result<String> {
+    let cache = std::env::var("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| std::env::var("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .context("no cache directory")?;
+    let dir = cache.join("notificationd").join("icons");
+    std::fs::create_dir_all(&dir)?;
+    let hash: String = Sha256::digest(data).iter().map(|b| format!("{b:02x}")).collect();
+    let path = dir.join(format!("{hash}.png"));
+    if !path.exists() {
+        std::fs::write(&path, data)?;
+    }
+    Ok(path.to_string_lossy().into_owned())
 }
@@ -0,0 +1,45 @@
+//! delays between reconnection attempts
+
+use std::time::Duration;
+
+use rand::Rng;
+
+const INITIAL: Duration = Duration::from_secs(1);
+const MAX: Duration = Duration::from_secs(5 * 60);
+
+/// Exponential backoff with jitter,
+/// so clients don't all reconnect at once when a server comes back
+pub struct Backoff {
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Self { attempt: 0 }
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// The delay before the next attempt, between half and all of the exponential delay
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = INITIAL.saturating_mul(2u32.saturating_pow(self.attempt)).min(MAX);
+        self.attempt = self.attempt.saturating_add(1);
+        let half = delay / 2;
+        half + rand::rng().random_range(Duration::ZERO..=half)
+    }
+}
+
+#[test]
+fn capped() {
+    let mut backoff = Backoff::new();
+    let first = backoff.next_delay();
+    assert!(first >= INITIAL / 2 && first <= INITIAL);
+    for _ in 0..100 {
+        assert!(backoff.next_delay() <= MAX);
+    }
+    assert!(backoff.next_delay() >= MAX / 2);
+    backoff.reset();
+    assert!(backoff.next_delay() <= INITIAL);
+}
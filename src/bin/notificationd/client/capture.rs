@@ -0,0 +1,145 @@
+//! forwarding notifications shown on this desktop to the server
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::thread;
+
+use anyhow::Context;
+use notificationd::notifications::Icon;
+use notificationd::notifications::NotificationDetails;
+use notificationd::notifications::Urgency;
+use notificationd::protocol;
+use notificationd::protocol::MAX_ICON_SIZE;
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
+use zbus::MatchRule;
+use zbus::blocking::Connection;
+use zbus::blocking::MessageIterator;
+use zbus::blocking::fdo::MonitoringProxy;
+use zbus::message::Type;
+use zbus::zvariant::OwnedValue;
+
+use crate::client::Upstream;
+
+/// Tag of captured notifications, so the client that captured them does not display them again
+pub const TAG: &str = "captured";
+
+/// Hint set on notifications we display, so they are not captured again
+pub const HINT: &str = "x-notificationd-id";
+
+/// Arguments of org.freedesktop.Notifications.Notify
+type Notify = (String, u32, String, String, String, Vec<String>, HashMap<String, OwnedValue>, i32);
+
+/// Monitor the session bus for notifications and send them to `upstream`
+pub fn start(upstream: Upstream) -> anyhow::Result<()> {
+    let connection = Connection::session().context("failed to connect to dbus")?;
+    let rule = MatchRule::builder()
+        .msg_type(Type::MethodCall)
+        .interface("org.freedesktop.Notifications")?
+        .member("Notify")?
+        .build();
+    MonitoringProxy::new(&connection)?
+        .become_monitor(&[rule], 0)
+        .context("failed to monitor the session bus")?;
+    info!("Capturing notifications for {}", upstream.connect);
+
+    thread::Builder::new()
+        .name(String::from("capture"))
+        .spawn(move || {
+            for msg in MessageIterator::from(connection) {
+                let msg = match msg {
+                    Ok(msg) => msg,
+                    Err(e) => return warn!("stopped capturing notifications: {e}"),
+                };
+                let header = msg.header();
+                if header.message_type() != Type::MethodCall
+                    || header.member().is_none_or(|member| member.as_str() != "Notify")
+                {
+                    continue;
+                }
+                let notify: Notify = match msg.body().deserialize() {
+                    Ok(notify) => notify,
+                    Err(e) => {
+                        debug!("invalid Notify call: {e}");
+                        continue;
+                    }
+                };
+                let Some(details) = captured(notify) else {
+                    continue;
+                };
+                match upstream.send(&details) {
+                    Ok((id, n)) => info!("Captured notification {id}, delivered to {n}"),
+                    Err(e) => warn!("failed to forward captured notification: {e:#}"),
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// The notification to send for a Notify call, unless we displayed it ourselves
+fn captured(notify: Notify) -> Option<NotificationDetails> {
+    let (app_name, _, app_icon, summary, body, _, hints, _) = notify;
+    if hints.contains_key(HINT) {
+        return None;
+    }
+    let mut details = details(&app_name, &app_icon, summary, body, &hints);
+    details.tags.insert(0, String::from(TAG));
+    Some(details)
+}
+
+/// Convert the arguments of a Notify call, tagging it with the application name
+pub fn details(
+    app_name: &str,
+    app_icon: &str,
+    summary: String,
+    body: String,
+    hints: &HashMap<String, OwnedValue>,
+) -> NotificationDetails {
+    let mut details = NotificationDetails::new();
+    details.title = Some(summary).filter(|s| !s.is_empty());
+    details.body = Some(body).filter(|s| !s.is_empty());
+    let app: String = app_name.to_lowercase().split_whitespace().collect::<Vec<_>>().join("-");
+    if protocol::is_argument(&app) {
+        details.tags.push(app);
+    }
+    if let Some(urgency) = hints
+        .get("urgency")
+        .and_then(|v| u8::try_from(v).ok())
+        .and_then(Urgency::from_level)
+    {
+        details.urgency = urgency;
+    }
+    let image_path = hints.get("image-path").and_then(|v| String::try_from(v.clone()).ok());
+    details.icon = image_path.into_iter().chain([app_icon.to_owned()]).find_map(|icon| icon_from(&icon));
+    details
+}
+
+/// An icon name, or a png file sent inline
+fn icon_from(icon: &str) -> Option<Icon> {
+    let path = icon.strip_prefix("file://").unwrap_or(icon);
+    if icon.is_empty() {
+        None
+    } else if Path::new(path).is_absolute() {
+        match std::fs::read(path) {
+            Ok(data) if path.ends_with(".png") && data.len() <= MAX_ICON_SIZE => Some(Icon::Png(data)),
+            _ => None,
+        }
+    } else {
+        Some(Icon::Name(icon.to_owned()))
+    }
+}
+
+#[test]
+fn skips_own_notifications() {
+    let hints = HashMap::from([(String::from(HINT), OwnedValue::from(1u64))]);
+    let notify = (String::new(), 0, String::new(), String::from("hi"), String::new(), vec![], hints, -1);
+    assert!(captured(notify).is_none());
+
+    let hints = HashMap::from([(String::from("urgency"), OwnedValue::from(2u8))]);
+    let notify = (String::from("Mail Client"), 0, String::from("mail"), String::from("hi"), String::new(), vec![], hints, -1);
+    let details = captured(notify).unwrap();
+    assert_eq!(details.tags, vec!["captured", "mail-client"]);
+    assert_eq!(details.urgency, Urgency::Critical);
+    assert!(matches!(details.icon, Some(Icon::Name(name)) if name == "mail"));
+}
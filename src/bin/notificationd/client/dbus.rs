@@ -10,6 +10,7 @@ use zbus::zvariant::Value;
 )]
 pub trait Notifications {
     /// Call the org.freedesktop.Notifications.Notify D-Bus method
+    #[allow(clippy::too_many_arguments)]
     fn notify(
         &self,
         app_name: &str,
@@ -21,4 +22,15 @@ pub trait Notifications {
         hints: HashMap<&str, &Value<'_>>,
         expire_timeout: i32,
     ) -> zbus::Result<u32>;
+
+    /// Call the org.freedesktop.Notifications.CloseNotification D-Bus method
+    fn close_notification(&self, id: u32) -> zbus::Result<()>;
+
+    /// The ActionInvoked signal
+    #[zbus(signal)]
+    fn action_invoked(&self, id: u32, action_key: &str) -> zbus::Result<()>;
+
+    /// The NotificationClosed signal
+    #[zbus(signal)]
+    fn notification_closed(&self, id: u32, reason: u32) -> zbus::Result<()>;
 }
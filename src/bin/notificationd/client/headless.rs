@@ -0,0 +1,107 @@
+//! acting as the notification server on machines without a desktop
+
+use std::collections::HashMap;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::thread;
+
+use anyhow::Context;
+use notificationd::notifications::NotificationDetails;
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
+use zbus::blocking::connection::Builder;
+use zbus::interface;
+use zbus::object_server::SignalEmitter;
+use zbus::zvariant::OwnedValue;
+
+use crate::client::Upstream;
+use crate::client::capture;
+use crate::config::Bus;
+use crate::service;
+use crate::varlink::VarlinkClientHandles;
+
+/// NotificationClosed reason for notifications closed by a CloseNotification call
+const REASON_CLOSED: u32 = 3;
+
+/// The org.freedesktop.Notifications interface, queueing notifications for the forwarding thread
+struct Notifications {
+    next_id: AtomicU32,
+    forward: mpsc::Sender<NotificationDetails>,
+}
+
+#[interface(name = "org.freedesktop.Notifications")]
+impl Notifications {
+    #[allow(clippy::too_many_arguments)]
+    fn notify(
+        &self,
+        app_name: &str,
+        _replaces_id: u32,
+        app_icon: &str,
+        summary: String,
+        body: String,
+        _actions: Vec<String>,
+        hints: HashMap<String, OwnedValue>,
+        _expire_timeout: i32,
+    ) -> u32 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        debug!("Notify {id} from {app_name}: {summary:?}");
+        let _ = self.forward.send(capture::details(app_name, app_icon, summary, body, &hints));
+        id
+    }
+
+    async fn close_notification(&self, id: u32, #[zbus(signal_emitter)] emitter: SignalEmitter<'_>) -> zbus::fdo::Result<()> {
+        // notifications are never shown, so they can always be closed
+        Self::notification_closed(&emitter, id, REASON_CLOSED).await?;
+        Ok(())
+    }
+
+    fn get_capabilities(&self) -> Vec<&str> {
+        vec!["body"]
+    }
+
+    fn get_server_information(&self) -> (&str, &str, &str, &str) {
+        ("notificationd", "LevitatingBusinessMan", env!("CARGO_PKG_VERSION"), "1.2")
+    }
+
+    #[zbus(signal)]
+    async fn notification_closed(emitter: &SignalEmitter<'_>, id: u32, reason: u32) -> zbus::Result<()>;
+}
+
+/// Claim org.freedesktop.Notifications on `bus` and forward every notification to `upstream`
+pub fn main(bus: Bus, upstream: Upstream) -> anyhow::Result<()> {
+    let (forward_tx, forward_rx) = mpsc::channel();
+    let iface = Notifications {
+        next_id: AtomicU32::new(1),
+        forward: forward_tx,
+    };
+    let builder = match bus {
+        Bus::Session => Builder::session()?,
+        Bus::System => Builder::system()?,
+    };
+    let _connection = builder
+        .name("org.freedesktop.Notifications")?
+        .serve_at("/org/freedesktop/Notifications", iface)?
+        .build()
+        .context(format!("failed to claim org.freedesktop.Notifications on the {bus:?} bus"))?;
+    info!("Forwarding notifications on the {bus:?} bus to {}", upstream.connect);
+
+    crate::varlink::init(None, Some(VarlinkClientHandles { upstream: upstream.clone(), connections: vec![] }), None)?;
+    service::watchdog(|| true);
+    service::notify(true, &format!("Forwarding to {}", upstream.connect));
+
+    let forwarder = thread::Builder::new()
+        .name(String::from("headless"))
+        .spawn(move || {
+            for details in forward_rx {
+                match upstream.send(&details) {
+                    Ok((id, n)) => info!("Forwarded notification {id}, delivered to {n}"),
+                    Err(e) => warn!("failed to forward notification: {e:#}"),
+                }
+            }
+        })?;
+    // the interface, and with it the sender, lives as long as the connection
+    forwarder.join().expect("forwarding thread panicked");
+    Ok(())
+}
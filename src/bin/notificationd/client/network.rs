@@ -0,0 +1,48 @@
+//! watching NetworkManager to reconnect as soon as the network is back
+
+use std::sync::mpsc;
+use std::thread;
+
+use tracing::debug;
+use tracing::warn;
+use zbus::blocking::Connection;
+use zbus::proxy;
+
+/// NM_STATE_CONNECTED_SITE, anything above can probably reach the server
+const CONNECTED_SITE: u32 = 60;
+
+#[proxy(
+    interface = "org.freedesktop.NetworkManager",
+    default_service = "org.freedesktop.NetworkManager",
+    default_path = "/org/freedesktop/NetworkManager"
+)]
+trait NetworkManager {
+    #[zbus(signal)]
+    fn state_changed(&self, state: u32) -> zbus::Result<()>;
+}
+
+/// Send a message to every channel when the network becomes connected.
+/// Without NetworkManager nothing is ever sent and the channels are closed.
+pub fn watch(txs: Vec<mpsc::Sender<()>>) {
+    thread::Builder::new()
+        .name(String::from("network"))
+        .spawn(move || {
+            if let Err(e) = watch_state(txs) {
+                warn!("not watching network changes: {e}");
+            }
+        })
+        .expect("failed to spawn network thread");
+}
+
+fn watch_state(mut txs: Vec<mpsc::Sender<()>>) -> zbus::Result<()> {
+    let connection = Connection::system()?;
+    let proxy = NetworkManagerProxyBlocking::new(&connection)?;
+    for signal in proxy.receive_state_changed()? {
+        let state = signal.args()?.state;
+        debug!("network state changed to {state}");
+        if state >= CONNECTED_SITE {
+            txs.retain(|tx| tx.send(()).is_ok());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,120 @@
+//! syncing invoked actions and dismissals of displayed notifications with the server
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
+use notificationd::protocol::Command;
+
+use crate::client::dbus::NotificationsProxyBlocking;
+use crate::tls;
+
+/// How many displayed notifications are remembered
+const MAX_SHOWN: usize = 1024;
+
+/// NotificationClosed reason for notifications dismissed by the user
+const REASON_DISMISSED: u32 = 2;
+
+/// Notifications displayed for a server
+#[derive(Default)]
+pub struct Shown {
+    /// Server ids by D-Bus notification id
+    ids: Mutex<BTreeMap<u32, usize>>,
+    /// The connection of the current session
+    writer: Mutex<Option<tls::Stream>>,
+}
+
+impl Shown {
+    pub fn shown(&self, dbus_id: u32, id: usize) {
+        let mut ids = self.ids.lock().unwrap();
+        ids.insert(dbus_id, id);
+        if ids.len() > MAX_SHOWN {
+            ids.pop_first();
+        }
+    }
+
+    /// Set the connection invoked actions and dismissals are sent over
+    pub fn set_writer(&self, writer: Option<tls::Stream>) {
+        *self.writer.lock().unwrap() = writer;
+    }
+
+    /// Forget a notification dismissed on another device, returning its D-Bus id
+    pub fn dismissed(&self, id: usize) -> Option<u32> {
+        let mut ids = self.ids.lock().unwrap();
+        let dbus_id = ids.iter().find(|(_, i)| **i == id).map(|(d, _)| *d)?;
+        ids.remove(&dbus_id);
+        Some(dbus_id)
+    }
+
+    fn send(&self, command: Command) {
+        match self.writer.lock().unwrap().as_mut() {
+            Some(writer) => {
+                if let Err(e) = writer.write_all(command.line(None).as_bytes()) {
+                    warn!("failed to send {command}: {e}");
+                }
+            }
+            None => warn!("not connected, dropping {command}"),
+        }
+    }
+
+    fn invoked(&self, dbus_id: u32, key: &str) {
+        let Some(id) = self.ids.lock().unwrap().get(&dbus_id).copied() else {
+            return;
+        };
+        info!("Invoked {key} on notification {id}");
+        self.send(Command::Invoke { id, key: key.to_owned() });
+    }
+
+    fn closed(&self, dbus_id: u32, reason: u32) {
+        let Some(id) = self.ids.lock().unwrap().remove(&dbus_id) else {
+            return;
+        };
+        // notifications that expired or were closed by us stay up elsewhere
+        if reason == REASON_DISMISSED {
+            info!("Dismissed notification {id}");
+            self.send(Command::Dismiss(id));
+        }
+    }
+}
+
+/// Listen for ActionInvoked and NotificationClosed signals of notifications in `shown`
+pub fn listen(iface: NotificationsProxyBlocking<'static>, shown: Arc<Shown>) {
+    let actions_iface = iface.clone();
+    let actions_shown = shown.clone();
+    thread::Builder::new()
+        .name(String::from("actions"))
+        .spawn(move || {
+            let signals = match actions_iface.receive_action_invoked() {
+                Ok(signals) => signals,
+                Err(e) => return warn!("not listening for actions: {e}"),
+            };
+            for signal in signals {
+                match signal.args() {
+                    Ok(args) => actions_shown.invoked(args.id, args.action_key),
+                    Err(e) => debug!("invalid ActionInvoked signal: {e}"),
+                }
+            }
+        })
+        .expect("failed to spawn actions thread");
+
+    thread::Builder::new()
+        .name(String::from("dismissals"))
+        .spawn(move || {
+            let signals = match iface.receive_notification_closed() {
+                Ok(signals) => signals,
+                Err(e) => return warn!("not listening for dismissals: {e}"),
+            };
+            for signal in signals {
+                match signal.args() {
+                    Ok(args) => shown.closed(args.id, args.reason),
+                    Err(e) => debug!("invalid NotificationClosed signal: {e}"),
+                }
+            }
+        })
+        .expect("failed to spawn dismissals thread");
+}
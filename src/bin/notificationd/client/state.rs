@@ -0,0 +1,76 @@
+//! state the client daemon keeps between runs
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use tracing::warn;
+
+/// The id of the last notification that was displayed,
+/// so missed notifications can be requested with SINCE
+pub struct LastId {
+    path: Option<PathBuf>,
+    pub id: Option<usize>,
+    /// No notification was displayed since [LastId::new_session]
+    new_session: bool,
+}
+
+impl LastId {
+    /// Load the last id displayed from `server`
+    pub fn load(server: &str) -> Self {
+        let path = state_dir().map(|dir| dir.join(format!("{server}.last_id")));
+        let id = path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|s| s.trim().parse().ok());
+        if path.is_none() {
+            warn!("no state directory, missed notifications will not be tracked");
+        }
+        Self { path, id, new_session: true }
+    }
+
+    /// Start tracking a new connection to the server
+    pub fn new_session(&mut self) {
+        self.new_session = true;
+    }
+
+    /// Record that a notification was displayed.
+    /// Within a session lower ids are ignored, as they may be relayed out of order.
+    /// The first id of a session is taken even if it is lower,
+    /// as the server only hands out ids below the last one after its ids were reset.
+    pub fn update(&mut self, id: usize) -> io::Result<()> {
+        let new_session = std::mem::take(&mut self.new_session);
+        match self.id {
+            Some(last) if last >= id && !new_session => return Ok(()),
+            Some(last) if last >= id => warn!("server ids went back from {last} to {id}, it was probably reset"),
+            _ => (),
+        }
+        self.id = Some(id);
+        if let Some(path) = &self.path {
+            fs::create_dir_all(path.parent().unwrap())?;
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, format!("{id}\n"))?;
+            fs::rename(&tmp, path)?;
+        }
+        Ok(())
+    }
+}
+
+fn state_dir() -> Option<PathBuf> {
+    std::env::var("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| std::env::var("HOME").map(|home| PathBuf::from(home).join(".local").join("state")))
+        .ok()
+        .map(|dir| dir.join("notificationd"))
+}
+
+#[test]
+fn lower_id_starts_over_in_new_session() {
+    let mut last_id = LastId { path: None, id: Some(500), new_session: true };
+    last_id.update(3).unwrap();
+    last_id.update(2).unwrap();
+    assert_eq!(last_id.id, Some(3));
+    last_id.new_session();
+    last_id.update(1).unwrap();
+    assert_eq!(last_id.id, Some(1));
+}
@@ -0,0 +1,104 @@
+//! connecting and logging in to the server
+
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::Arc;
+
+use anyhow::bail;
+use notificationd::client;
+use notificationd::client::Client;
+use notificationd::notifications::NotificationDetails;
+use notificationd::protocol::Command;
+use notificationd::protocol::Reply;
+use notificationd::protocol::ServerLine;
+use tracing::info;
+use tracing::warn;
+
+use crate::client::LoginFailed;
+use crate::tls;
+
+/// A server and how to log in to it
+#[derive(Clone)]
+pub struct Upstream {
+    pub connect: String,
+    pub login: String,
+    pub password: Option<String>,
+    pub tls: Option<Arc<rustls::ClientConfig>>,
+    pub starttls: bool,
+}
+
+impl Upstream {
+    pub fn open_stream(&self) -> anyhow::Result<tls::Stream> {
+        let sock = TcpStream::connect(&self.connect)?;
+        keepalive(&sock);
+        let mut stream = tls::Stream::plain(sock);
+
+        if let Some(config) = &self.tls {
+            if self.starttls {
+                stream.write_all(Command::StartTls.line(None).as_bytes())?;
+                let line = read_line_unbuffered(&mut stream)?;
+                match ServerLine::parse(&line) {
+                    Some(ServerLine::Reply(Reply { result: Ok(_), command, .. })) if command == "STARTTLS" => (),
+                    _ => bail!("server refused STARTTLS: {line}"),
+                }
+            }
+            stream.upgrade(tls::client_connection(config.clone(), &self.connect)?);
+        }
+
+        info!("Connected to {}{}", stream.peer_addr()?, if stream.is_tls() { " using TLS" } else { "" });
+        Ok(stream)
+    }
+
+    pub fn login_message(&self) -> String {
+        Command::Login {
+            user: Some(self.login.clone()),
+            password: self.password.clone(),
+        }
+        .line(None)
+    }
+
+    /// Send a notification over a new connection,
+    /// returning its id and how many consumers it was relayed to
+    pub fn send(&self, details: &NotificationDetails) -> anyhow::Result<(i64, i64)> {
+        let mut client = Client::new(self.open_stream()?);
+        match client.login(&self.login, self.password.as_deref()) {
+            Err(client::Error::Failed(Reply { result: Err(code), .. })) => return Err(LoginFailed(code.to_string()).into()),
+            res => res?,
+        }
+        let sent = client.send(details)?;
+        client.quit()?;
+        for reply in &sent.rejected {
+            if let Err(code) = &reply.result {
+                warn!("{} of notification {id:?} was rejected: {code}", reply.command, id = sent.id);
+            }
+        }
+        // servers before ids were part of the reply
+        let id = sent.id.map_or(-1, |id| id as i64);
+        Ok((id, sent.delivered as i64))
+    }
+}
+
+/// Detect connections that died while suspended or roaming
+fn keepalive(sock: &TcpStream) {
+    use nix::sys::socket::setsockopt;
+    use nix::sys::socket::sockopt;
+    let res = setsockopt(sock, sockopt::KeepAlive, &true)
+        .and_then(|()| setsockopt(sock, sockopt::TcpKeepIdle, &60))
+        .and_then(|()| setsockopt(sock, sockopt::TcpKeepInterval, &15))
+        .and_then(|()| setsockopt(sock, sockopt::TcpKeepCount, &4));
+    if let Err(e) = res {
+        warn!("failed to enable keepalive: {e}");
+    }
+}
+
+/// Read a single line without reading ahead,
+/// so nothing sent after it is consumed
+fn read_line_unbuffered(stream: &mut impl Read) -> std::io::Result<String> {
+    let mut line = vec![];
+    let mut byte = [0u8];
+    while stream.read(&mut byte)? == 1 && byte[0] != b'\n' {
+        line.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&line).trim_end().to_owned())
+}
@@ -0,0 +1,174 @@
+//! configuration file for the server and client
+//!
+//! The first file found of `--config`, `$XDG_CONFIG_HOME/notificationd/config.toml`
+//! and `/etc/notificationd/config.toml` is used.
+//! Options given on the command line override those in the file.
+//! See `examples/config.toml` for all options.
+
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::de::Error;
+
+use notificationd::duration;
+
+use crate::server::SlowConsumers;
+use crate::tls;
+
+const SYSTEM_CONFIG: &str = "/etc/notificationd/config.toml";
+
+#[derive(serde_derive::Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub client: ClientConfig,
+}
+
+#[derive(serde_derive::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Address to listen on
+    pub bind: String,
+    /// Store notifications so they can be looked up later
+    pub persistent: bool,
+    /// Sqlite database to store notifications in
+    pub database: PathBuf,
+    /// Credentials file the server authenticates logins with
+    pub credentials: Option<PathBuf>,
+    /// Delete notifications older than this many seconds
+    #[serde(deserialize_with = "age")]
+    pub retention: Option<u64>,
+    /// Unix socket to listen on, logins over it are those of the connecting user
+    pub socket: Option<PathBuf>,
+    /// Permissions of the unix socket
+    pub socket_mode: u32,
+    /// Messages a connection may have waiting to be written
+    pub queue_size: usize,
+    /// What to do with connections whose queue is full
+    pub slow_consumers: SlowConsumers,
+    /// Hosts callbacks may be posted to, where `.example.org` includes its subdomains
+    pub callback_hosts: Vec<String>,
+    /// Varlink address notificationctl connects to, instead of the one for the current user
+    pub varlink: Option<String>,
+    pub tls: tls::ServerTlsArgs,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: String::from("0.0.0.0:6606"),
+            persistent: true,
+            database: PathBuf::from("/tmp/notificationd.sqlite3"),
+            credentials: None,
+            retention: None,
+            socket: None,
+            socket_mode: 0o666,
+            queue_size: 1024,
+            slow_consumers: SlowConsumers::default(),
+            callback_hosts: vec![],
+            varlink: None,
+            tls: tls::ServerTlsArgs::default(),
+        }
+    }
+}
+
+#[derive(serde_derive::Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    /// Servers to receive notifications from
+    pub servers: Vec<String>,
+    /// File containing the password or token the client logs in with
+    pub password_file: Option<PathBuf>,
+    pub tls: tls::ClientTlsArgs,
+    pub display: DisplayConfig,
+    /// Forward notifications shown on this desktop to the first server
+    pub capture: bool,
+    /// Instead of displaying notifications, act as the notification server on this bus
+    /// and forward every notification to the first server
+    pub headless: Option<Bus>,
+}
+
+/// A D-Bus message bus
+#[derive(serde_derive::Deserialize, clap::ValueEnum, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Bus {
+    Session,
+    System,
+}
+
+/// How received notifications are shown
+#[derive(serde_derive::Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DisplayConfig {
+    /// Milliseconds until a notification expires,
+    /// 0 never expires them and -1 leaves it to the notification server
+    pub timeout: i32,
+    /// Icon for notifications without one
+    pub default_icon: String,
+    /// Show the sender as the application name
+    pub show_sender: bool,
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        Self {
+            timeout: 0,
+            default_icon: String::from("dialog-information"),
+            show_sender: true,
+        }
+    }
+}
+
+/// Read the configuration at `path`, or otherwise from the default locations
+pub fn load(path: Option<&Path>) -> anyhow::Result<Config> {
+    let path = match path {
+        Some(path) => path.to_owned(),
+        None => match default_paths().into_iter().find(|path| path.exists()) {
+            Some(path) => path,
+            None => return Ok(Config::default()),
+        },
+    };
+    let contents = fs::read_to_string(&path).context(format!("failed to read {}", path.display()))?;
+    let config = toml::from_str(&contents).context(format!("invalid configuration in {}", path.display()))?;
+    tracing::info!("Loaded configuration from {}", path.display());
+    Ok(config)
+}
+
+fn default_paths() -> Vec<PathBuf> {
+    let user = std::env::var("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| std::env::var("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .ok()
+        .map(|dir| dir.join("notificationd").join("config.toml"));
+    user.into_iter().chain([PathBuf::from(SYSTEM_CONFIG)]).collect()
+}
+
+/// An age in seconds, or a string like `30d`
+fn age<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(serde_derive::Deserialize)]
+    #[serde(untagged)]
+    enum Age {
+        Seconds(u64),
+        Text(String),
+    }
+    match Age::deserialize(deserializer)? {
+        Age::Seconds(seconds) => Ok(Some(seconds)),
+        Age::Text(text) => duration::parse(&text)
+            .map(Some)
+            .ok_or_else(|| D::Error::custom(format!("invalid age {text}"))),
+    }
+}
+
+#[test]
+fn example() {
+    let config: Config = toml::from_str(include_str!("../../../examples/config.toml")).unwrap();
+    assert_eq!(config.server.retention, Some(90 * 24 * 60 * 60));
+    assert_eq!(config.server.callback_hosts, [".example.org"]);
+    assert_eq!(config.client.servers.len(), 1);
+    assert!(config.client.tls.tls);
+    assert!(toml::from_str::<Config>("[server]\nport = 1").is_err());
+}
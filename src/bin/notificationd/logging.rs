@@ -18,7 +18,7 @@ pub fn init() -> anyhow::Result<()> {
                 .with_writer(io::stderr);
 
     let syslog = {
-        static IDENTITY: &'static CStr = c"notificationd";
+        static IDENTITY: &CStr = c"notificationd";
         let (options, facility) = Default::default();
         let writer = syslog_tracing::Syslog::new(IDENTITY, options, facility)
             .ok_or(anyhow!("failed to create syslog writer"))?;
@@ -39,6 +39,7 @@ pub fn init() -> anyhow::Result<()> {
 }
 
 /// Trait for logging different kinds of errors
+#[allow(dead_code)]
 pub trait LogError {
     /// If this result is an error, log it as such
     fn log(self) -> Self;
@@ -1,25 +1,87 @@
+use std::path::PathBuf;
+
 use clap::Parser;
 
 mod client;
-mod protocol;
+mod config;
 mod server;
+mod service;
 mod logging;
+mod tls;
 mod varlink;
 
 #[derive(Parser)]
 struct Args {
-    #[arg(long, default_value = "0.0.0.0:6606")]
-    bind: String,
-    #[arg(short, long)]
-    client: Option<String>,
+    /// Configuration file to use instead of the default locations
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Address to listen on
+    #[arg(long)]
+    bind: Option<String>,
+    /// Run as client, receiving from this server or those in the configuration
+    #[arg(short, long, num_args = 0..=1)]
+    client: Option<Option<String>>,
+    /// Sqlite database to store notifications in
+    #[arg(long)]
+    database: Option<PathBuf>,
+    /// Don't store notifications
+    #[arg(long)]
+    no_persistence: bool,
+    /// Unix socket to listen on, logins over it are those of the connecting user
+    #[arg(long)]
+    socket: Option<PathBuf>,
+    /// Credentials file the server authenticates logins with
+    #[arg(long)]
+    credentials: Option<PathBuf>,
+    /// Varlink address notificationctl connects to, instead of the one for the current user
+    #[arg(long)]
+    varlink: Option<String>,
+    /// Forward notifications shown on this desktop to the server
+    #[arg(long)]
+    capture: bool,
+    /// Act as the notification server on this bus, forwarding notifications instead of displaying them
+    #[arg(long, num_args = 0..=1, default_missing_value = "session")]
+    headless: Option<config::Bus>,
+    /// File containing the password or token the client logs in with
+    #[arg(long)]
+    password_file: Option<PathBuf>,
+    #[command(flatten)]
+    server_tls: tls::ServerTlsArgs,
+    #[command(flatten)]
+    client_tls: tls::ClientTlsArgs,
 }
 
 fn main() -> anyhow::Result<()> {
     logging::init().expect("Failed to initialize logging");
     let args = Args::parse();
-    if let Some(server) = args.client {
-        Ok(client::main(server)?)
+    if let Some(server) = args.client.clone() {
+        let mut config = config::load(args.config.as_deref())?.client;
+        if let Some(server) = server {
+            config.servers = vec![server];
+        }
+        config.password_file = args.password_file.or(config.password_file);
+        config.capture |= args.capture;
+        config.headless = args.headless.or(config.headless);
+        config.tls = args.client_tls.or(config.tls);
+        let res = client::main(config);
+        service::stopping();
+        res
     } else {
-        Ok(server::main(args.bind)?)
+        let res = server::main(server_config(&args)?, move || server_config(&args));
+        service::stopping();
+        res
     }
 }
+
+/// The server configuration, with the options given on the command line
+fn server_config(args: &Args) -> anyhow::Result<config::ServerConfig> {
+    let mut config = config::load(args.config.as_deref())?.server;
+    config.bind = args.bind.clone().unwrap_or(config.bind);
+    config.database = args.database.clone().unwrap_or(config.database);
+    config.persistent &= !args.no_persistence;
+    config.credentials = args.credentials.clone().or(config.credentials);
+    config.socket = args.socket.clone().or(config.socket);
+    config.varlink = args.varlink.clone().or(config.varlink);
+    config.tls = args.server_tls.clone().or(config.tls);
+    Ok(config)
+}
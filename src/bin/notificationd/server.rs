@@ -1,37 +1,121 @@
-use anyhow;
-use std::io;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
 use std::net::TcpListener;
+use std::num::NonZero;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::atomic::AtomicUsize;
-#[cfg(target_os = "linux")]
-use libsystemd as systemd;
+use std::time::Duration;
+use std::time::Instant;
 
-use client::ClientHandle;
+use anyhow::Context;
+use nix::sys::signal::SigSet;
+use nix::sys::signal::Signal;
 
+use actions::ActionRoute;
+pub use actions::CallbackUrl;
+pub use actions::InvokeError;
+pub use actions::is_callback_url;
+use auth::CredentialStore;
+use auth::Permissions;
+use database::Database;
+use database::NotificationDetailsDatabaseExt;
+pub use database::DbError;
+pub use database::Purge;
+use event_loop::EventLoop;
+use event_loop::Listener;
+use pool::Pool;
+pub use queue::SlowConsumers;
+use queue::QueueStats;
+use registry::Registry;
+use routing::Login;
+use notificationd::notifications::NotificationDetails;
+use notificationd::protocol;
+use notificationd::protocol::ServerMessage;
+
+use crate::config::ServerConfig;
+use crate::service;
+use crate::tls;
+
+mod actions;
+mod auth;
 mod client;
 mod database;
+mod event_loop;
+mod pool;
+mod queue;
+mod registry;
+mod routing;
+
+/// How often notifications past the retention are deleted
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long connections get to send their last replies when shutting down
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub static NOTIFICATION_COUNTER: AtomicUsize = AtomicUsize::new(1);
+
+/// Numbers unix socket connections, which have no address
+static UNIX_COUNTER: AtomicUsize = AtomicUsize::new(1);
 
-pub static NOTIFICATION_COUNTER: AtomicUsize = AtomicUsize::new(0);
+/// The other end of a connection
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    /// A local process, with credentials from SO_PEERCRED
+    Unix { id: usize, uid: u32, pid: i32 },
+}
+
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{addr}"),
+            Peer::Unix { id, uid, pid } => write!(f, "unix#{id} (uid {uid}, pid {pid})"),
+        }
+    }
+}
 
 pub fn next_id() -> usize {
     NOTIFICATION_COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
 }
 
+/// Set the id the next notification will get
 pub fn set_id(id: usize) {
     NOTIFICATION_COUNTER.store(id, std::sync::atomic::Ordering::Relaxed);
 }
 
 pub struct ServerState {
-    pub(self) clients: Vec<ClientHandle>,
-    pub(self) db: Option<rusqlite::Connection>,
+    /// Credentials for LOGIN, when absent any login is accepted
+    pub(self) auth: Option<Arc<CredentialStore>>,
+    /// Recent notifications with actions, by id
+    pub(self) routes: BTreeMap<usize, ActionRoute>,
+    /// Delete notifications older than this many seconds
+    pub(self) retention: Option<u64>,
+    /// No new connections are accepted
+    pub(self) shutting_down: bool,
+    /// Messages a connection may have waiting to be written
+    pub(self) queue_size: usize,
+    pub(self) slow_consumers: SlowConsumers,
+    pub(self) queue_stats: Arc<QueueStats>,
+    /// Hosts callbacks may be posted to
+    pub(self) callback_hosts: Vec<String>,
 }
 
 impl ServerState {
     pub fn new() -> Self {
         Self {
-            clients: vec![],
-            db: None,
+            auth: None,
+            routes: BTreeMap::new(),
+            retention: None,
+            shutting_down: false,
+            queue_size: 1024,
+            slow_consumers: SlowConsumers::default(),
+            queue_stats: Arc::new(QueueStats::default()),
+            callback_hosts: vec![],
         }
     }
 }
@@ -40,86 +124,481 @@ impl ServerState {
 pub struct ServerHandle {
     /// Address this server is bound at
     pub bind: Arc<String>,
+    /// TLS configuration for the TLS listener and STARTTLS
+    pub tls: Option<Arc<rustls::ServerConfig>>,
     /// Mutable state of the server
     pub(self) state: Arc<Mutex<ServerState>>,
+    pub(self) clients: Arc<Registry>,
+    /// The database threads, if this server is persistent
+    pub(self) db: Option<Database>,
+    /// Runs the commands of connections
+    pub(self) workers: Arc<Pool>,
+    /// Posts invoked actions to callbacks
+    pub(self) callbacks: Arc<Pool>,
 }
 
 impl ServerHandle {
-    pub fn new(addr: String, state: ServerState) -> Self {
-        Self {
+    pub fn new(
+        addr: String,
+        tls: Option<Arc<rustls::ServerConfig>>,
+        state: ServerState,
+        db: Option<Database>,
+    ) -> std::io::Result<Self> {
+        let threads = std::thread::available_parallelism().map_or(4, NonZero::get).max(4);
+        Ok(Self {
             bind: Arc::new(addr),
+            tls,
             state: Arc::new(Mutex::new(state)),
-        }
-    }
-    pub fn add_client(&self, handle: ClientHandle) {
-        self.state.lock().unwrap().clients.push(handle);
+            clients: Arc::new(Registry::default()),
+            db,
+            workers: Arc::new(Pool::new("worker", threads)?),
+            callbacks: Arc::new(Pool::bounded("callback", actions::CALLBACK_THREADS, actions::MAX_PENDING_CALLBACKS)?),
+        })
     }
+    #[allow(dead_code)]
     pub fn broadcast(&self, msg: String) {
-        let state = self.state.lock().unwrap();
-        for c in &state.clients {
+        self.clients.for_each(|c| {
             let _ = c.write(&msg);
-        }
+        });
     }
-    pub fn broadcast_notification(&self, msg: String) -> u32 {
-        let state = self.state.lock().unwrap();
+    /// Relay a notification to the consumers it is addressed to
+    pub fn broadcast_notification(&self, details: &NotificationDetails, msg: String) -> u32 {
         let mut n = 0;
-        for c in &state.clients {
-            if c.state.lock().unwrap().consume == true {
-                if c.write(&msg).is_ok() {
-                    n += 1;
+        self.clients.for_each(|c| {
+            let addressed = {
+                let client_state = c.state.lock().unwrap();
+                client_state.consume
+                    && client_state
+                        .login
+                        .as_ref()
+                        .is_some_and(|login| routing::is_addressed_to(details, login))
+            };
+            if addressed && c.write_notification(details.id.unwrap_or(0), &msg).is_ok() {
+                n += 1;
+            }
+        });
+        n
+    }
+    /// Check the credentials of a login, returning what it is allowed to do
+    pub fn authenticate(&self, login: &str, secret: Option<&str>) -> Option<Permissions> {
+        // verifying is slow, so don't hold the lock
+        let auth = self.state.lock().unwrap().auth.clone();
+        match (auth, secret) {
+            (None, _) => Some(Permissions::OPEN),
+            (Some(store), Some(secret)) => store.authenticate(login, secret).map(|c| {
+                tracing::debug!("{login} authenticated with {:?} of {}", c.kind, c.user);
+                c.permissions
+            }),
+            (Some(_), None) => None,
+        }
+    }
+    /// Run a closure on a database thread, if this server is persistent
+    pub fn with_db<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut rusqlite::Connection) -> R + Send + 'static,
+    ) -> Option<R> {
+        self.db.as_ref().and_then(|db| db.run(f))
+    }
+    /// Delete a notification.
+    /// If `owner` is set, only delete it if it was sent by that user.
+    pub fn delete_notification(&self, id: u32, owner: Option<&str>) -> Result<(), DbError> {
+        let owner = owner.map(str::to_owned);
+        self.with_db(move |db| {
+            let details = NotificationDetails::load(db, id)?;
+            if let Some(owner) = owner
+                && details.user.as_deref().map(routing::base_user) != Some(routing::base_user(&owner))
+            {
+                return Err(DbError::Forbidden);
+            }
+            NotificationDetails::delete(db, id)?;
+            tracing::info!("Deleted notification {id}");
+            Ok(())
+        })
+        .unwrap_or(Err(DbError::NoDb))
+    }
+    /// Delete the notifications matching `filter`, which only match those of `owner` if given
+    pub fn purge_notifications(&self, filter: &Purge, owner: Option<&str>) -> Result<usize, DbError> {
+        let mut filter = filter.clone();
+        if let Some(owner) = owner {
+            match &filter.user {
+                Some(user) if routing::base_user(user) != routing::base_user(owner) => return Err(DbError::Forbidden),
+                Some(_) => (),
+                None => filter.user = Some(routing::base_user(owner).to_owned()),
+            }
+        }
+        self.with_db(move |db| {
+            let n = NotificationDetails::purge(db, &filter)?;
+            tracing::info!("Purged {n} notifications matching {filter:?}");
+            Ok(n)
+        })
+        .unwrap_or(Err(DbError::NoDb))
+    }
+    /// Store and relay a notification from `user` sent over the connection `peer`,
+    /// returning its id and how many consumers it was relayed to
+    pub fn send(&self, mut details: NotificationDetails, user: &str, peer: Option<Peer>) -> (usize, u32) {
+        let id = next_id();
+        details.user = Some(user.to_owned());
+        details.id = Some(id);
+        if !details.ephemeral {
+            let stored = details.clone();
+            match self.with_db(move |db| stored.save(db)) {
+                Some(Ok(n)) => {
+                    tracing::info!("Saved notification from {} (id {})", user, id);
+                    tracing::debug!("{} row affected", n);
                 }
+                Some(Err(e)) => tracing::error!("Error saving {}: {e}", id),
+                None => (),
             }
+        } else if let Some(Err(e)) = self.with_db(move |db| database::skip_id(db, id)) {
+            tracing::error!("Error reserving id {id}: {e}");
         }
-        n
+        {
+            let mut state = self.state.lock().unwrap();
+            if !details.actions.is_empty() || details.callback.is_some() {
+                let mut route = ActionRoute { details: details.clone(), peer };
+                route.details.icon = None;
+                state.routes.insert(id, route);
+                if state.routes.len() > actions::MAX_ROUTES {
+                    state.routes.pop_first();
+                }
+            }
+        }
+
+        let n = if details.quiet {
+            0
+        } else {
+            protocol::notify_message(&details).map_or(0, |message| self.broadcast_notification(&details, message))
+        };
+        (id, n)
+    }
+
+    /// Route an action `login` invoked on a notification back to its sender,
+    /// returning to how many connections it was sent
+    pub fn invoke(&self, id: usize, key: &str, login: &Login) -> Result<u32, InvokeError> {
+        let route = self.state.lock().unwrap().routes.get(&id).cloned();
+        let route = match route {
+            Some(route) => route,
+            None => {
+                let key = u32::try_from(id).map_err(|_| InvokeError::NotFound)?;
+                let details = self
+                    .with_db(move |db| NotificationDetails::load(db, key).ok())
+                    .flatten()
+                    .ok_or(InvokeError::NotFound)?;
+                ActionRoute { details, peer: None }
+            }
+        };
+        if !routing::is_addressed_to(&route.details, login) {
+            return Err(InvokeError::Forbidden);
+        }
+        if !route.details.actions.iter().any(|(k, _)| k == key) {
+            return Err(InvokeError::UnknownAction);
+        }
+        let sender = route.details.user.clone().unwrap_or_default();
+        let login = login.name.as_str();
+        tracing::info!("{login} invoked {key} on {id} of {sender}");
+
+        let msg = ServerMessage::Invoked { id, key: key.to_owned(), login: login.to_owned() }.line();
+        let senders = self
+            .clients
+            .filter(|c| c.state.lock().unwrap().login.as_ref().is_some_and(|l| l.name == sender));
+        let n = match senders.iter().find(|c| Some(c.peer) == route.peer) {
+            Some(connection) => connection.write(&msg).is_ok() as u32,
+            None => senders.iter().filter(|c| c.write(&msg).is_ok()).count() as u32,
+        };
+
+        if let Some(url) = route.details.callback {
+            match CallbackUrl::parse(&url) {
+                // the allowed hosts may have changed since it was sent
+                Some(callback) if self.allows_callback(&callback) => {
+                    let (key, login, target) = (key.to_owned(), login.to_owned(), url.clone());
+                    let spawned = self.callbacks.spawn(move || {
+                        if let Err(e) = actions::post_callback(&callback, id, &key, &login) {
+                            tracing::warn!("Callback to {target} failed: {e:#}");
+                        }
+                    });
+                    if !spawned {
+                        tracing::warn!("Too many callbacks pending, not posting {id} to {url}");
+                    }
+                }
+                _ => tracing::warn!("Not posting {id} to {url}, its host is not allowed"),
+            }
+        }
+        Ok(n)
     }
-    pub(self) fn listen_incoming(&self, listener: TcpListener) -> io::Result<()> {
+
+    /// Record that `login` dismissed a notification and close it on the other devices
+    /// of its user, returning how many connections it was relayed to
+    pub fn dismiss(&self, id: usize, login: &Login, peer: Peer) -> Result<u32, DbError> {
+        let user = routing::base_user(&login.name);
+        let dismissed_by = login.clone();
+        let stored = self.with_db(move |db| -> Result<(), DbError> {
+            let key = u32::try_from(id).map_err(|_| DbError::NotFound)?;
+            let details = NotificationDetails::load(db, key)?;
+            if !routing::is_addressed_to(&details, &dismissed_by) {
+                return Err(DbError::Forbidden);
+            }
+            database::dismiss(db, id, routing::base_user(&dismissed_by.name))?;
+            Ok(())
+        });
+        match stored {
+            Some(Err(DbError::Forbidden)) => return Err(DbError::Forbidden),
+            Some(Err(DbError::Sqlite(e))) => tracing::error!("Failed to store dismissal of {id}: {e}"),
+            // notifications that were not stored can still be dismissed
+            _ => (),
+        }
+        tracing::info!("{} dismissed {id}", login.name);
+
+        let msg = ServerMessage::Dismiss(id).line();
+        let n = self
+            .clients
+            .filter(|c| {
+                let state = c.state.lock().unwrap();
+                c.peer != peer && state.consume && state.login.as_ref().is_some_and(|l| routing::base_user(&l.name) == user)
+            })
+            .iter()
+            .filter(|c| c.write(&msg).is_ok())
+            .count() as u32;
+        Ok(n)
+    }
+
+    /// Periodically delete notifications older than the retention, if there is one
+    fn expire(&self) {
         loop {
-            let (stream, _peer) = listener.accept()?;
-            self.add_client(ClientHandle::new(stream, self.clone())?);
+            let retention = self.state.lock().unwrap().retention;
+            if let Some(retention) = retention {
+                let filter = Purge {
+                    older_than: Some(retention),
+                    ..Purge::default()
+                };
+                if let Err(e) = self.purge_notifications(&filter, None) {
+                    tracing::error!("Failed to delete old notifications: {e}");
+                }
+            }
+            std::thread::sleep(RETENTION_INTERVAL);
+        }
+    }
+    /// Check if the configuration allows posting to the host of `callback`
+    pub fn allows_callback(&self, callback: &CallbackUrl) -> bool {
+        callback.is_allowed(&self.state.lock().unwrap().callback_hosts)
+    }
+    /// Apply the parts of a changed configuration that don't need a restart
+    fn reload(&self, config: &ServerConfig) -> anyhow::Result<()> {
+        let auth = config.credentials
+            .as_ref()
+            .map(|path| CredentialStore::load(path))
+            .transpose()?
+            .map(Arc::new);
+        let mut state = self.state.lock().unwrap();
+        state.auth = auth;
+        state.retention = config.retention;
+        state.callback_hosts = config.callback_hosts.clone();
+        tracing::info!("Reloaded credentials, retention and callback hosts, other changes need a restart");
+        Ok(())
+    }
+    /// Stop accepting connections and close the existing ones once their replies are written,
+    /// then close the database
+    fn shutdown(&self) {
+        self.state.lock().unwrap().shutting_down = true;
+        let clients = self.clients.filter(|_| true);
+        let closing: Vec<_> = clients
+            .iter()
+            .map(|c| {
+                let _ = c.write(&ServerMessage::Notice(String::from("The server is shutting down.")).line());
+                c.close()
+            })
+            .collect();
+        // one deadline for all of them, so slow connections don't add up
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        for closed in closing {
+            let _ = closed.recv_timeout(deadline.saturating_duration_since(Instant::now()));
+        }
+        // after the queries sent before, so no notification is stored halfway
+        if let Some(db) = &self.db {
+            db.close();
         }
     }
     pub fn clients_len(&self) -> usize {
-        self.state.lock().unwrap().clients.len()
+        self.clients.len()
     }
     pub fn has_db(&self) -> bool {
-        self.state.lock().unwrap().db.is_some()
+        self.db.is_some()
+    }
+    /// Queue size, policy, and the dropped and disconnected counters
+    pub fn queue_config(&self) -> (usize, SlowConsumers, u64, u64) {
+        let state = self.state.lock().unwrap();
+        let stats = &state.queue_stats;
+        (
+            state.queue_size,
+            state.slow_consumers,
+            stats.dropped.load(std::sync::atomic::Ordering::Relaxed),
+            stats.disconnected.load(std::sync::atomic::Ordering::Relaxed),
+        )
+    }
+    /// The queued and dropped messages of every connection
+    pub fn queues(&self) -> Vec<(Peer, Option<String>, usize, u64)> {
+        let mut v = vec![];
+        self.clients.for_each(|client| {
+            let login = client.state.lock().unwrap().login.as_ref().map(|l| l.name.clone());
+            let (queued, dropped) = client.queue_status();
+            v.push((client.peer, login, queued, dropped));
+        });
+        v
     }
-    pub fn who(&self) -> Vec<(String, std::net::SocketAddr, bool)> {
+    pub fn who(&self) -> Vec<(String, Peer, bool)> {
         let mut v = vec![];
-        for client in &self.state.lock().unwrap().clients {
+        self.clients.for_each(|client| {
             let state = client.state.lock().unwrap();
-            if let Some(login) = &state.name {
-                v.push((login.to_string(), client.peer, state.consume));
+            if let Some(login) = &state.login {
+                v.push((login.name.clone(), client.peer, state.consume));
             }
-        }
-        return v;
+        });
+        v
     }
 }
 
-pub fn main(bind: String) -> anyhow::Result<()> {
+/// Bind a unix socket at `path` with permissions `mode`,
+/// replacing the socket of a previous run
+fn bind_unix(path: &Path, mode: u32) -> anyhow::Result<UnixListener> {
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path).context(format!("failed to bind {}", path.display()))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// Run the server until it fails or is stopped.
+/// On SIGHUP the configuration is read again with `reload`.
+pub fn main(
+    config: ServerConfig,
+    reload: impl Fn() -> anyhow::Result<ServerConfig> + Send + 'static,
+) -> anyhow::Result<()> {
+    // handled by the signal thread, threads spawned later inherit the mask
+    let mut signals = SigSet::empty();
+    for signal in [Signal::SIGTERM, Signal::SIGINT, Signal::SIGHUP] {
+        signals.add(signal);
+    }
+    signals.thread_block()?;
+
     let mut server_state = ServerState::new();
-    let persistence = true;
-    let db_path = "/tmp/notificationd.sqlite3";
-    server_state.db = if persistence {
-        let mut db = rusqlite::Connection::open(db_path)?;
+    server_state.retention = config.retention;
+    server_state.queue_size = config.queue_size;
+    server_state.slow_consumers = config.slow_consumers;
+    server_state.callback_hosts = config.callback_hosts;
+    server_state.auth = config.credentials
+        .map(|path| CredentialStore::load(&path))
+        .transpose()?
+        .map(Arc::new);
+    let db = if config.persistent {
+        let mut db = database::open(&config.database)?;
         database::setup_database(&mut db)?;
-        tracing::info!("Opened database {db_path}");
-        Some(db)
+        set_id(database::next_id(&db)?);
+        tracing::info!("Opened database {}", config.database.display());
+        Some(Database::spawn(db, &config.database)?)
     } else {
         None
     };
 
-    let listener = TcpListener::bind(&bind)?;
-    tracing::info!("Listening on {}", bind);
+    if server_state.slow_consumers == SlowConsumers::Spill && db.is_none() {
+        tracing::warn!("Notifications can't be spilled without a database, dropping them instead");
+        server_state.slow_consumers = SlowConsumers::DropOldest;
+    }
+
+    let tls_config = tls::server_config(&config.tls)?;
+
+    let mut listeners = service::listeners()?;
+    if listeners.is_empty() {
+        listeners.plain.push(TcpListener::bind(&config.bind)?);
+        if let Some(tls_bind) = &config.tls.tls_bind {
+            listeners.tls.push(TcpListener::bind(tls_bind)?);
+        }
+        if let Some(path) = &config.socket {
+            listeners.unix.push(bind_unix(path, config.socket_mode)?);
+        }
+    } else {
+        tracing::info!("Using sockets passed by systemd");
+    }
+    if !listeners.tls.is_empty() && tls_config.is_none() {
+        anyhow::bail!("listening for TLS requires a certificate and key");
+    }
 
-    let server_handle = ServerHandle::new(bind, server_state);
+    let bind = listeners
+        .plain
+        .first()
+        .and_then(|l| l.local_addr().ok())
+        .map_or(config.bind, |addr| addr.to_string());
+    let server_handle = ServerHandle::new(bind, tls_config, server_state, db)?;
 
-    crate::varlink::init(Some(server_handle.clone()))?;
+    if server_handle.has_db() {
+        let handle = server_handle.clone();
+        std::thread::Builder::new()
+            .name(String::from("retention"))
+            .spawn(move || handle.expire())?;
+    }
 
-    #[cfg(target_os = "linux")]
-    if systemd::daemon::booted() {
-        systemd::daemon::notify(false, &[systemd::daemon::NotifyState::Ready])?;
+    let mut event_listeners = vec![];
+    let tcp = listeners.plain.into_iter().map(|l| (l, false));
+    for (listener, implicit_tls) in tcp.chain(listeners.tls.into_iter().map(|l| (l, true))) {
+        let kind = if implicit_tls { "TLS " } else { "" };
+        tracing::info!("Listening for {kind}connections on {}", listener.local_addr()?);
+        listener.set_nonblocking(true)?;
+        let listener = mio::net::TcpListener::from_std(listener);
+        event_listeners.push(Listener::Tcp { listener, implicit_tls });
     }
+    for listener in listeners.unix {
+        let addr = listener.local_addr()?;
+        let path = addr.as_pathname().map_or(String::from("?"), |p| p.display().to_string());
+        tracing::info!("Listening for local connections on {path}");
+        listener.set_nonblocking(true)?;
+        event_listeners.push(Listener::Unix(mio::net::UnixListener::from_std(listener)));
+    }
+
+    // the event loop only returns on errors, which are fatal
+    let (result_tx, result_rx) = std::sync::mpsc::channel();
+    let event_loop = EventLoop::new(event_listeners, server_handle.clone())?;
+    let loop_result_tx = result_tx.clone();
+    std::thread::Builder::new()
+        .name(String::from("event loop"))
+        .spawn(move || {
+            let res = event_loop.run();
+            let _ = loop_result_tx.send(res.context("event loop quit"));
+        })?;
+
+    crate::varlink::init(Some(server_handle.clone()), None, config.varlink)?;
+
+    // a deadlocked server can't take its own lock
+    let handle = server_handle.clone();
+    service::watchdog(move || {
+        drop(handle.state.lock());
+        true
+    });
+    service::notify(true, "Listening");
+
+    let handle = server_handle.clone();
+    std::thread::Builder::new()
+        .name(String::from("signals"))
+        .spawn(move || {
+            loop {
+                match signals.wait() {
+                    Ok(Signal::SIGHUP) => {
+                        tracing::info!("Reloading configuration");
+                        service::reloading();
+                        if let Err(e) = reload().and_then(|config| handle.reload(&config)) {
+                            tracing::error!("Failed to reload: {e:#}");
+                        }
+                        service::notify(true, "Listening");
+                    }
+                    Ok(signal) => {
+                        tracing::info!("Received {signal}, shutting down");
+                        service::stopping();
+                        handle.shutdown();
+                        let _ = result_tx.send(Ok(()));
+                        return;
+                    }
+                    Err(e) => tracing::error!("Failed to wait for signals: {e}"),
+                }
+            }
+        })?;
 
-    Ok(server_handle.listen_incoming(listener)?)
+    result_rx.recv()?
 }
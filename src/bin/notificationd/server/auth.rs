@@ -0,0 +1,165 @@
+//! credential store for LOGIN
+//!
+//! The store is a plain text file with one credential per line:
+//! ```text
+//! # <user> <password|token> <argon2 hash> [permission,...]
+//! rein password $argon2id$v=19$m=19456,t=2,p=1$... admin
+//! p2pool-webhook token $argon2id$v=19$m=19456,t=2,p=1$...
+//! ```
+//! Passwords default to `send,consume`, tokens to `send`.
+//! Hashes can be created with `notificationctl hash`.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+use anyhow::anyhow;
+use anyhow::bail;
+use argon2::Argon2;
+use argon2::PasswordHash;
+use argon2::PasswordVerifier;
+
+use crate::server::routing;
+
+/// What an authenticated connection is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    /// Send notifications
+    pub send: bool,
+    /// Receive notifications and read the history
+    pub consume: bool,
+    /// Manage notifications of other users
+    pub admin: bool,
+}
+
+impl Permissions {
+    /// Permissions of a login on a server without a credential store
+    pub const OPEN: Self = Self {
+        send: true,
+        consume: true,
+        admin: false,
+    };
+
+    pub const NONE: Self = Self {
+        send: false,
+        consume: false,
+        admin: false,
+    };
+
+    fn parse(list: &str) -> anyhow::Result<Self> {
+        let mut permissions = Self::NONE;
+        for p in list.split(',') {
+            match p {
+                "send" => permissions.send = true,
+                "consume" => permissions.consume = true,
+                "admin" => permissions.admin = true,
+                _ => bail!("unknown permission {p}"),
+            }
+        }
+        Ok(permissions)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CredentialKind {
+    Password,
+    Token,
+}
+
+#[derive(Debug, Clone)]
+pub struct Credential {
+    pub user: String,
+    pub kind: CredentialKind,
+    /// argon2 hash in PHC string format
+    hash: String,
+    pub permissions: Permissions,
+}
+
+impl Credential {
+    fn verify(&self, secret: &str) -> bool {
+        match PasswordHash::new(&self.hash) {
+            Ok(hash) => Argon2::default().verify_password(secret.as_bytes(), &hash).is_ok(),
+            Err(e) => {
+                tracing::error!("invalid hash for {}: {e}", self.user);
+                false
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CredentialStore {
+    credentials: Vec<Credential>,
+}
+
+impl CredentialStore {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)
+            .context(format!("failed to read credentials from {}", path.display()))?;
+        let store = Self::parse(&contents).context(format!("in {}", path.display()))?;
+        tracing::info!("Loaded {} credentials from {}", store.credentials.len(), path.display());
+        Ok(store)
+    }
+
+    pub fn parse(contents: &str) -> anyhow::Result<Self> {
+        let mut credentials = vec![];
+        for (n, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (user, kind, hash, permissions) = match fields[..] {
+                [user, kind, hash] => (user, kind, hash, None),
+                [user, kind, hash, permissions] => (user, kind, hash, Some(permissions)),
+                _ => bail!("line {}: expected <user> <password|token> <hash> [permissions]", n + 1),
+            };
+            let kind = match kind {
+                "password" => CredentialKind::Password,
+                "token" => CredentialKind::Token,
+                _ => bail!("line {}: unknown credential kind {kind}", n + 1),
+            };
+            PasswordHash::new(hash).map_err(|e| anyhow!("line {}: {e}", n + 1))?;
+            let permissions = match (permissions, kind) {
+                (Some(list), _) => Permissions::parse(list).context(format!("line {}", n + 1))?,
+                (None, CredentialKind::Password) => Permissions::OPEN,
+                (None, CredentialKind::Token) => Permissions {
+                    send: true,
+                    ..Permissions::NONE
+                },
+            };
+            credentials.push(Credential {
+                user: user.to_owned(),
+                kind,
+                hash: hash.to_owned(),
+                permissions,
+            });
+        }
+        Ok(Self { credentials })
+    }
+
+    /// Check a secret for a login, returning the matching credential.
+    /// Credentials are stored per user, so `user@host` logins use those of `user`.
+    pub fn authenticate(&self, login: &str, secret: &str) -> Option<&Credential> {
+        let user = routing::base_user(login);
+        self.credentials
+            .iter()
+            .filter(|c| c.user == user)
+            .find(|c| c.verify(secret))
+    }
+}
+
+#[test]
+fn token_permissions() {
+    // hash of "hunter2"
+    let store = CredentialStore::parse(
+        "# comment\n\
+        bot token $argon2id$v=19$m=19456,t=2,p=1$BJXreT82UjWzYy0FD8Kxpg$AVoZMacGKqfXikAtLfaNrn6qM8b7jYcKBWuJ79SWKVQ\n",
+    )
+    .unwrap();
+    let credential = store.authenticate("bot@host", "hunter2").unwrap();
+    assert_eq!(credential.kind, CredentialKind::Token);
+    assert!(credential.permissions.send && !credential.permissions.consume);
+    assert!(store.authenticate("bot", "hunter3").is_none());
+    assert!(store.authenticate("other", "hunter2").is_none());
+}
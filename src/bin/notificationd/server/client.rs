@@ -1,454 +1,543 @@
-use std::io;
-use std::io::BufReader;
-use std::io::prelude::*;
-use std::net::SocketAddr;
-use std::net::TcpStream;
+use std::collections::VecDeque;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 use std::sync::mpsc;
-use std::thread;
 
+use notificationd::notifications::Icon;
 use notificationd::notifications::NotificationDetails;
-use crate::protocol;
-use crate::protocol::parser;
-use crate::server;
+use notificationd::protocol;
+use notificationd::protocol::Command;
+use notificationd::protocol::Delete;
+use notificationd::protocol::ErrorCode;
+use notificationd::protocol::Reply;
+use notificationd::protocol::parser;
+use notificationd::protocol::MAX_ICON_SIZE;
+use crate::server::actions::CallbackUrl;
+use crate::server::Peer;
+use crate::server::event_loop::Wakeups;
+use crate::server::queue::Closed;
+use crate::server::queue::Outgoing;
+use crate::server::queue::Queue;
 use crate::server::ServerHandle;
+use crate::server::auth::Permissions;
 use crate::server::database::NotificationDetailsDatabaseExt;
+use crate::server::database::Purge;
+use crate::server::routing;
+use crate::server::routing::Login;
 
-use tracing::{error, warn, info, debug, trace};
+use tracing::{error, warn, debug, trace};
+
+/// Lines read but not handled before the event loop stops reading
+const MAX_INBOX: usize = 256;
 
 pub struct ClientState {
-    pub name: Option<String>,
+    pub login: Option<Arc<Login>>,
     pub details: NotificationDetails,
     pub consume: bool,
+    /// What this connection may do, set on LOGIN
+    pub permissions: Permissions,
 }
 
 impl ClientState {
     pub fn new() -> Self {
         ClientState {
-            name: None,
+            login: None,
             consume: false,
+            permissions: Permissions::NONE,
             details: NotificationDetails::new(),
         }
     }
 }
 
+/// Lines read from a connection, waiting for their commands to run
+#[derive(Default)]
+struct Inbox {
+    /// None marks the end of the input
+    lines: VecDeque<Option<String>>,
+    /// A worker is running the commands
+    running: bool,
+    /// The event loop stopped reading until the lines are handled
+    paused: bool,
+    /// STARTTLS was accepted, lines are dropped until the event loop switched to TLS
+    upgrading: bool,
+}
+
 #[derive(Clone)]
 pub struct ClientHandle {
-    pub peer: SocketAddr,
+    /// Identifies the connection in the event loop
+    pub id: usize,
+    pub peer: Peer,
     pub state: Arc<Mutex<ClientState>>,
-    // only used for closing
-    stream: Arc<TcpStream>,
     pub server: ServerHandle,
-    write_channel: mpsc::Sender<String>,
+    pub(super) queue: Arc<Queue>,
+    inbox: Arc<Mutex<Inbox>>,
+    /// Wrapped in TLS, or will be once the STARTTLS reply is written
+    tls: Arc<AtomicBool>,
+    wakeups: Arc<Wakeups>,
 }
 
 impl ClientHandle {
-    pub fn new(stream: TcpStream, server: ServerHandle) -> io::Result<Self> {
-        let peer = stream.peer_addr()?;
-        let write_stream = stream.try_clone()?;
-        let read_stream = BufReader::new(stream.try_clone()?);
-        let (tx, rx) = mpsc::channel();
-        let write_thread = thread::Builder::new().name(format!("writer {peer}"));
-        let read_thread = thread::Builder::new().name(format!("reader {peer}"));
-        let state = Arc::new(Mutex::new(ClientState::new()));
-
-        let handle = Self {
+    pub fn new(id: usize, peer: Peer, tls: bool, server: ServerHandle, wakeups: Arc<Wakeups>) -> Self {
+        let queue = {
+            let state = server.state.lock().unwrap();
+            Arc::new(Queue::new(state.queue_size, state.slow_consumers, state.queue_stats.clone()))
+        };
+        debug!("Connection from {peer}");
+        Self {
+            id,
             peer,
-            state,
+            state: Arc::new(Mutex::new(ClientState::new())),
             server,
-            stream: Arc::new(stream),
-            write_channel: tx,
-        };
+            queue,
+            inbox: Arc::new(Mutex::new(Inbox::default())),
+            tls: Arc::new(AtomicBool::new(tls)),
+            wakeups,
+        }
+    }
+
+    /// Handle a line read by the event loop on a worker, None when the input ended
+    pub fn receive(&self, line: Option<String>) {
+        let mut inbox = self.inbox.lock().unwrap();
+        if inbox.upgrading && line.is_some() {
+            warn!("{}: dropped a line sent in plain text after STARTTLS", self.peer);
+            return;
+        }
+        inbox.lines.push_back(line);
+        if !inbox.running {
+            inbox.running = true;
+            let handle = self.clone();
+            self.server.workers.spawn(move || handle.work());
+        }
+    }
 
-        let handle_clone = handle.clone();
+    /// Check if too many lines wait to be handled, the worker wakes the event loop once they are
+    pub fn paused(&self) -> bool {
+        let mut inbox = self.inbox.lock().unwrap();
+        inbox.paused = inbox.lines.len() >= MAX_INBOX;
+        inbox.paused
+    }
 
-        debug!("Connection from {peer}");
+    /// The event loop switched to TLS, lines are read from the session from now on
+    pub fn upgraded(&self) {
+        self.inbox.lock().unwrap().upgrading = false;
+    }
 
-        write_thread.spawn(move || {
-            match Self::writer(write_stream, rx) {
-                Ok(()) => error!("Writer thread returned Ok"),
-                Err(e) => {
-                    if e.downcast_ref::<mpsc::RecvError>().is_some() {
-                        trace!("all senders were dropped");
-                    } else {
-                        warn!("{e:#?}")
+    /// Handle the lines in the inbox, one at a time
+    fn work(&self) {
+        loop {
+            let line = {
+                let mut inbox = self.inbox.lock().unwrap();
+                if inbox.paused && inbox.lines.len() < MAX_INBOX / 2 {
+                    inbox.paused = false;
+                    self.wake();
+                }
+                match inbox.lines.pop_front() {
+                    Some(line) => line,
+                    None => {
+                        inbox.running = false;
+                        return;
                     }
                 }
+            };
+            let Some(line) = line else {
+                debug!("Connection closed");
+                self.remove();
+                return;
+            };
+            if let Err(e) = self.handle_line(&line) {
+                warn!("{e}");
+                // stays running, so nothing else is handled
+                self.remove();
+                return;
             }
-        }).unwrap();
+        }
+    }
+
+    fn handle_line(&self, line: &str) -> anyhow::Result<()> {
+        trace!("received: {line}");
+        match parser::line(line, false) {
+            Ok((_remaining, msg)) => self.handle_message(msg),
+            Err(e) => Ok(self.reply(Reply::err(None, "ERR", ErrorCode::Parse).with_trailing(e.to_string()))?),
+        }
+    }
 
-        read_thread.spawn(move || {
-            match Self::reader(read_stream, &handle) {
-                Ok(()) => debug!("Connection closed"),
-                Err(e) => warn!("{e}"),
+    /// Queue the notifications from `from` on that were left for replay
+    pub fn replay(&self, from: usize) {
+        let login = self.state.lock().unwrap().login.clone();
+        let offset = u32::try_from(from.saturating_sub(1)).unwrap_or(u32::MAX);
+        let missed = login.and_then(|login| self.server.with_db(move |db| NotificationDetails::load_missed(db, &login, offset)));
+        let missed = match missed {
+            Some(Ok(missed)) => missed,
+            Some(Err(e)) => {
+                error!("failed to replay notifications: {e}");
+                vec![]
             }
-            handle.remove();
-        }).unwrap();
+            None => vec![],
+        };
+        debug!("replaying {} notifications from {from}", missed.len());
+        self.queue.refill(missed.iter().filter_map(|n| Some((n.id?, protocol::notify_message(n)?))));
+        self.wake();
+    }
 
-        Ok(handle_clone)
+    /// Have the event loop write what was queued
+    pub fn wake(&self) {
+        self.wakeups.wake(self.id);
     }
 
-    /// The reader thread
-    fn reader(reader: BufReader<TcpStream>, handle: &ClientHandle) -> anyhow::Result<()> {
-        for line in reader.lines() {
-            let line = line?;
-            trace!("received: {line}");
-            match parser::line(&line, false) {
-                Ok((_remaining, msg)) => handle.handle_message(msg)?,
-                Err(e) => handle.write(&format!("-ERR PARSE: {e}\r\n"))?,
-            }
-        }
-        Ok(())
+    pub fn write(&self, msg: &str) -> Result<(), Closed> {
+        self.push(Outgoing::Message(msg.to_owned()))
     }
 
-    /// The writer thread
-    fn writer(mut writer: TcpStream, rx: mpsc::Receiver<String>) -> anyhow::Result<()> {
-        loop {
-            let str = rx.recv()?;
-            writer.write(str.as_bytes())?;
+    /// Write a relayed notification, which may be replayed from the database instead
+    pub fn write_notification(&self, id: usize, msg: &str) -> Result<(), Closed> {
+        self.push(Outgoing::Notification(id, msg.to_owned()))
+    }
+
+    fn push(&self, msg: Outgoing) -> Result<(), Closed> {
+        let result = self.queue.push(msg);
+        if let Err(Closed { overflowed: true }) = result {
+            warn!("disconnecting {} for not reading its messages", self.peer);
         }
+        if !matches!(result, Err(Closed { overflowed: false })) {
+            self.wake();
+        }
+        result
     }
 
-    pub fn write(&self, msg: &str) -> Result<(), mpsc::SendError<String>> {
-        self.write_channel.send(msg.to_owned())
+    /// Close the connection once everything written before is sent.
+    /// The returned channel is notified, or dropped, when it is closed.
+    pub fn close(&self) -> mpsc::Receiver<()> {
+        let (tx, rx) = mpsc::channel();
+        let _ = self.push(Outgoing::Close(tx));
+        rx
     }
 
-    /// remove its copy in the server, consuming self
-    pub fn remove(self) {
-        let mut server_state = self.server.state.lock().unwrap();
-        let i = server_state.clients.iter().position(|c| Arc::ptr_eq(&c.state, &self.state));
-        if let Some(i) = i {
-            server_state.clients.remove(i);
-        } else {
-            warn!("client handler already removed")
+    /// Messages waiting to be written, and how many were dropped
+    pub fn queue_status(&self) -> (usize, u64) {
+        (self.queue.len(), self.queue.dropped())
+    }
+
+    /// Remove it from the server, the connection is closed once everything queued is written
+    pub fn remove(&self) {
+        self.queue.close();
+        self.server.clients.remove(self.id);
+        self.wake();
+    }
+
+    /// The user of the process on the other end of a unix socket
+    fn local_user(&self) -> Option<String> {
+        let Peer::Unix { uid, .. } = self.peer else {
+            return None;
+        };
+        let name = nix::unistd::User::from_uid(uid.into()).ok().flatten().map(|u| u.name);
+        Some(name.unwrap_or(uid.to_string()))
+    }
+
+    /// Check a login, over a unix socket it has to be the connecting user unless that is root
+    fn authenticate(&self, login: &str, secret: Option<&str>) -> Option<Permissions> {
+        match (self.peer, self.local_user()) {
+            (Peer::Unix { uid: 0, .. }, _) => Some(Permissions::OPEN),
+            (Peer::Unix { .. }, Some(user)) if routing::base_user(login) == user => Some(Permissions::OPEN),
+            (Peer::Unix { .. }, _) => None,
+            (Peer::Tcp(_), _) => self.server.authenticate(login, secret),
         }
     }
 
+    fn reply(&self, reply: Reply) -> Result<(), Closed> {
+        self.write(&reply.line())
+    }
+
     // to return an error here means to kill the connection
     //#[tracing::instrument(skip_all, fields(cmd=msg.command)]
     pub fn handle_message(&self, msg: protocol::parser::Message) -> anyhow::Result<()> {
         if msg.sign.is_some() {
-            self.write(&protocol::reply(
-                msg.id,
-                false,
-                "ERR",
-                vec!["INVALID_MESSAGE"],
-                Some("You can't sent a reply message to a server."),
-            ))?;
+            self.reply(
+                Reply::err(msg.id, "ERR", ErrorCode::InvalidMessage)
+                    .with_trailing("You can't sent a reply message to a server."),
+            )?;
             return Ok(());
         }
 
-        let cmd = msg.command.to_uppercase();
-        match cmd.as_ref() {
-            "LOGIN" => {
-                let user = msg.arguments.get(0);
-                let password = msg.arguments.get(1);
+        let id = msg.id;
+        let (user, permissions) = {
+            let state = self.state.lock().unwrap();
+            (state.login.clone(), state.permissions)
+        };
+        let (command, user) = match (Command::parse(&msg), user) {
+            (Ok(Command::Login { user, password }), _) => return self.login(id, user, password),
+            (Ok(Command::StartTls), _) => return self.start_tls(id),
+            // user needs to be logged in first
+            (_, None) => {
+                self.reply(Reply::err(id, "ERR", ErrorCode::NoLogin).with_trailing("Please login first."))?;
+                return Ok(());
+            }
+            (Err(reply), Some(_)) => {
+                self.reply(reply)?;
+                return Ok(());
+            }
+            (Ok(command), Some(user)) => (command, user),
+        };
+
+        let allowed = match command {
+            Command::Send => permissions.send,
+            Command::Consume(_)
+            | Command::History(_)
+            | Command::Since(_)
+            | Command::Who
+            | Command::Invoke { .. }
+            | Command::Dismiss(_) => permissions.consume,
+            _ => true,
+        };
+        if !allowed {
+            self.reply(
+                Reply::err(id, command.name(), ErrorCode::Forbidden).with_trailing("Your credentials do not allow this."),
+            )?;
+            return Ok(());
+        }
 
-                match user {
-                    None => self.write(&protocol::reply(
-                        msg.id,
-                        false,
-                        "LOGIN",
-                        vec!["MISSING_ARG"],
-                        None,
-                    ))?,
-                    Some(user) => {
-                        let user_ = &mut self.state.lock().unwrap().name;
-                        match user_ {
-                            Some(user_) => self.write(&protocol::reply(
-                                msg.id,
-                                false,
-                                "LOGIN",
-                                vec!["ALREADY_LOGGED_IN"],
-                                Some(&format!(
-                                    "You are already logged in as {}. Please reconnect.",
-                                    user_
-                                )),
-                            ))?,
-                            None => {
-                                *user_ = Some(user.to_owned());
-                                self.write(&protocol::reply(
-                                    msg.id,
-                                    true,
-                                    "LOGIN",
-                                    vec![],
-                                    Some(&format!("Welcome {}", user)),
-                                ))?;
-                                debug!("user {user} logged in");
-                            }
-                        };
+        match command {
+            Command::Login { .. } | Command::StartTls => unreachable!(),
+            Command::Title(title) => {
+                self.state.lock().unwrap().details.title = Some(title);
+            }
+            Command::Body { line, reset } => {
+                let mut state = self.state.lock().unwrap();
+                match line {
+                    Some(line) => {
+                        if let Some(body) = &mut state.details.body
+                            && !reset
+                        {
+                            body.push_str(&line);
+                            body.push('\n');
+                        } else {
+                            state.details.body = Some(format!("{line}\n"))
+                        }
                     }
+                    None => state.details.body = None,
                 }
             }
-            _ => {
-                // user needs to be logged in first
-                if self.state.lock().unwrap().name.is_none() {
-                    self.write(&protocol::reply(
-                        msg.id,
-                        false,
-                        "ERR",
-                        vec!["NO_LOGIN"],
-                        Some("Please login first."),
-                    ))?
-                } else {
-                    let user = self.state.lock().unwrap().name.clone().unwrap();
-                    match cmd.as_ref() {
-                        "TITLE" => match msg.trailing {
-                            Some(title) => {
-                                self.state.lock().unwrap().details.title = Some(title.clone())
-                            }
-                            None => self.write(&protocol::reply(
-                                msg.id,
-                                false,
-                                "TITLE",
-                                vec!["MISSING_TRAILING"],
-                                None,
-                            ))?,
-                        }
-                        "BODY" => {
-                            let reset = msg
-                                .arguments
-                                .first()
-                                .is_some_and(|a| a.to_uppercase() == "RST");
-                            match msg.trailing {
-                                Some(line) => {
-                                    let mut state = self.state.lock().unwrap();
-                                    if let Some(body) = &mut state.details.body
-                                        && !reset
-                                    {
-                                        body.push_str(&line);
-                                        body.push('\n');
-                                    } else {
-                                        state.details.body = Some(format!("{line}\n"))
-                                    }
-                                }
-                                None => {
-                                    if reset {
-                                        self.state.lock().unwrap().details.body = None;
-                                    } else {
-                                        self.write(&protocol::reply(
-                                            msg.id,
-                                            false,
-                                            "BODY",
-                                            vec!["MISSING_TRAILING"],
-                                            None,
-                                        ))?
-                                    }
-                                }
-                            }
-                        }
-                        "SEND" => {
-                            let mut details = self.state.lock().unwrap().details.clone();
-                            details.user = Some(user.clone());
-                            details.id = Some(server::next_id());
-                            if let Some(db) = &mut self.server.state.lock().unwrap().db {
-                                match details.save(db) {
-                                    Ok(n) => {
-                                        details.id = Some(db.last_insert_rowid() as usize);
-                                        server::set_id(details.id.unwrap());
-                                        info!("Saved notification from {} (id {})", details.user.unwrap(), details.id.unwrap());
-                                        debug!(
-                                            "{} row affected",
-                                            n
-                                        );
-                                    }
-                                    Err(e) => error!("Error saving {}: {e}", details.id.unwrap()),
-                                }
-                            }
-
-                            if details.id.is_none() {
-                                details.id = Some(server::next_id());
-                            }
-
-                            let mut notify_msg =
-                                format!("$NOTIFY_START {} {}\r\n", user, details.id.unwrap());
-
-                            if let Some(title) = details.title {
-                                notify_msg += &format!("$TITLE: {}\r\n", title)
-                            }
-
-                            if !details.tags.is_empty() {
-                                notify_msg += &format!("$TAGS: {}\r\n", details.tags.join(" "))
-                            }
-
-                            if let Some(body) = details.body {
-                                for line in body.lines() {
-                                    notify_msg += &format!("$BODY: {}\r\n", line);
+            Command::Icon { icon, reset } => {
+                let too_large = {
+                    let mut state = self.state.lock().unwrap();
+                    match icon {
+                        Some(Icon::Png(chunk)) => match &mut state.details.icon {
+                            Some(Icon::Png(data)) if !reset => {
+                                if data.len() + chunk.len() > MAX_ICON_SIZE {
+                                    true
+                                } else {
+                                    data.extend(chunk);
+                                    false
                                 }
                             }
-
-                            notify_msg += &format!("$NOTIFY_END {}\r\n", details.id.unwrap());
-
-                            let n = self.server.broadcast_notification(notify_msg);
-                            self.write(&protocol::reply(
-                                msg.id,
-                                true,
-                                "SEND",
-                                vec![&n.to_string()],
-                                None,
-                            ))?
-                        }
-                        "RESET" => {
-                            self.state.lock().unwrap().details = NotificationDetails::new();
-                        }
-                        "VERSION" => {
-                            self.write(&protocol::reply(
-                                msg.id,
-                                true,
-                                "VERSION",
-                                vec![env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")],
-                                None,
-                            ))?
-                        }
-                        "CONSUME" => {
-                            let arg = &msg
-                                .arguments
-                                .first()
-                                .map_or(String::from("on"), |a| a.to_lowercase());
-
-                            if arg == "on" || arg == "true" {
-                                self.state.lock().unwrap().consume = true;
-                                self.write(&protocol::reply(
-                                    msg.id,
-                                    true,
-                                    "CONSUME",
-                                    vec![arg],
-                                    None,
-                                ))?
-                            } else if arg == "off" || arg == "false" {
-                                self.state.lock().unwrap().consume = false;
-                                self.write(&protocol::reply(
-                                    msg.id,
-                                    true,
-                                    "CONSUME",
-                                    vec![arg],
-                                    None,
-                                ))?
-                            } else {
-                                self.write(&protocol::reply(
-                                    msg.id,
-                                    false,
-                                    "CONSUME",
-                                    vec!["INVALID_ARG"],
-                                    None,
-                                ))?
+                            icon => {
+                                *icon = Some(Icon::Png(chunk));
+                                false
                             }
+                        },
+                        icon => {
+                            state.details.icon = icon;
+                            false
                         }
-                        "QUIT" => {
-                            self.stream.shutdown(std::net::Shutdown::Both)?;
-                        }
-                        "HISTORY" => {
-                            if let Some(db) = &mut self.server.state.lock().unwrap().db {
-                                let limit = msg.arguments.first().map(|s| s.parse::<u32>()).transpose();
-                                match limit {
-                                    Err(_) => {
-                                        self.write(&protocol::reply(
-                                            msg.id,
-                                            false,
-                                            "HISTORY",
-                                            vec!["INVALID_ARG"],
-                                            None,
-                                        ))?
-                                    },
-                                    Ok(limit) => {
-                                        match NotificationDetails::load_all(db, limit) {
-                                            Ok(notifications) => {
-                                                let mut replies = vec![];
-                                                for notifications in notifications {
-                                                    replies.push(protocol::reply(
-                                                        msg.id,
-                                                        true,
-                                                        "HISTORY",
-                                                        vec![
-                                                            &notifications.id.unwrap().to_string(),
-                                                            &notifications.user.unwrap().to_string(),
-                                                        ],
-                                                        Some(&notifications.timestamp.unwrap().to_string()),
-                                                    ));
+                    }
+                };
+                if too_large {
+                    self.reply(
+                        Reply::err(id, "ICON", ErrorCode::TooLarge)
+                            .with_trailing(format!("Icons may be at most {MAX_ICON_SIZE} bytes.")),
+                    )?
+                }
+            }
+            Command::Quiet(quiet) => {
+                self.state.lock().unwrap().details.quiet = quiet;
+            }
+            Command::Ephemeral(ephemeral) => {
+                self.state.lock().unwrap().details.ephemeral = ephemeral;
+            }
+            Command::To(recipients) => {
+                self.state.lock().unwrap().details.recipients = recipients;
+            }
+            Command::Tags(tags) => {
+                self.state.lock().unwrap().details.tags = tags;
+            }
+            Command::Urgency(urgency) => {
+                self.state.lock().unwrap().details.urgency = urgency;
+            }
+            Command::Action { key, label } => {
+                let actions = &mut self.state.lock().unwrap().details.actions;
+                match actions.iter_mut().find(|(k, _)| *k == key) {
+                    Some(action) => action.1 = label,
+                    None => actions.push((key, label)),
+                }
+            }
+            Command::Callback(None) => {
+                self.state.lock().unwrap().details.callback = None;
+            }
+            Command::Callback(Some(url)) => match CallbackUrl::parse(&url) {
+                Some(callback) if self.server.allows_callback(&callback) => {
+                    self.state.lock().unwrap().details.callback = Some(url);
+                }
+                Some(callback) => self.reply(
+                    Reply::err(id, "CALLBACK", ErrorCode::Forbidden)
+                        .with_trailing(format!("Callbacks to {} are not allowed.", callback.host)),
+                )?,
+                None => self.reply(
+                    Reply::err(id, "CALLBACK", ErrorCode::InvalidArg)
+                        .with_trailing("Callbacks must be http or https URLs without credentials or a fragment."),
+                )?,
+            },
+            Command::Invoke { id: notification, key } => match self.server.invoke(notification, &key, &user) {
+                Ok(n) => self.reply(Reply::ok(id, "INVOKE", vec![n.to_string()]))?,
+                Err(e) => self.reply(Reply::err(id, "INVOKE", e.code()).with_trailing(e.to_string()))?,
+            },
+            Command::Dismiss(notification) => match self.server.dismiss(notification, &user, self.peer) {
+                Ok(n) => self.reply(Reply::ok(id, "DISMISS", vec![n.to_string()]))?,
+                Err(e) => self.reply(Reply::err(id, "DISMISS", e.code()).with_trailing(e.to_string()))?,
+            },
+            Command::Send => {
+                let details = self.state.lock().unwrap().details.clone();
+                let (notification, n) = self.server.send(details, &user.name, Some(self.peer));
+                self.reply(Reply::ok(id, "SEND", vec![n.to_string(), notification.to_string()]))?
+            }
+            Command::Reset => {
+                self.state.lock().unwrap().details = NotificationDetails::new();
+            }
+            Command::Version => self.reply(Reply::ok(
+                id,
+                "VERSION",
+                vec![String::from(env!("CARGO_PKG_NAME")), String::from(env!("CARGO_PKG_VERSION"))],
+            ))?,
+            Command::Consume(consume) => {
+                self.state.lock().unwrap().consume = consume;
+                let arg = if consume { "on" } else { "off" };
+                self.reply(Reply::ok(id, "CONSUME", vec![String::from(arg)]))?
+            }
+            Command::Quit => {
+                // replies to earlier commands are still sent
+                self.close();
+            }
+            Command::History(limit) => {
+                let login = user.clone();
+                let result = self.server.with_db(move |db| NotificationDetails::load_visible(db, &login, limit));
+                match result {
+                    Some(Ok(notifications)) => {
+                        let replies: String = notifications
+                            .iter()
+                            .filter_map(|n| Reply::history(id, n))
+                            .flatten()
+                            .map(|reply| reply.line())
+                            .collect();
+                        self.write(&replies)?;
+                    }
+                    Some(Err(e)) => {
+                        error!("db failure: {e}");
+                        self.reply(Reply::err(id, "HISTORY", ErrorCode::DbFail).with_trailing(e.to_string()))?
+                    }
+                    None => self.reply(Reply::err(id, "HISTORY", ErrorCode::NoDb))?,
+                }
+            }
+            Command::Since(offset) => {
+                let login = user.clone();
+                let result = self.server.with_db(move |db| NotificationDetails::load_missed(db, &login, offset));
+                match result {
+                    Some(Ok(missed)) => {
+                        let mut replay: String = missed.iter().filter_map(protocol::notify_message).collect();
+                        replay += &Reply::ok(id, "SINCE", vec![missed.len().to_string()]).line();
+                        self.write(&replay)?
+                    }
+                    Some(Err(e)) => {
+                        error!("db failure: {e}");
+                        self.reply(Reply::err(id, "SINCE", ErrorCode::DbFail).with_trailing(e.to_string()))?
+                    }
+                    None => self.reply(Reply::err(id, "SINCE", ErrorCode::NoDb))?,
+                }
+            }
+            Command::Delete(delete) => {
+                // only admins may delete notifications of other users
+                let owner = if permissions.admin { None } else { Some(user.name.as_str()) };
+                let result = match delete {
+                    Delete::Id(notification) => self.server.delete_notification(notification, owner).map(|()| 1),
+                    Delete::Matching { user, tag, older_than } => {
+                        self.server.purge_notifications(&Purge { user, tag, older_than }, owner)
+                    }
+                };
+                match result {
+                    Ok(n) => self.reply(Reply::ok(id, "DELETE", vec![n.to_string()]))?,
+                    Err(e) => self.reply(Reply::err(id, "DELETE", e.code()).with_trailing(e.to_string()))?,
+                }
+            }
+            Command::Who => {
+                for (login, peer, consume) in self.server.who() {
+                    let mut args = vec![login];
+                    if consume {
+                        args.push(String::from("CONSUME"));
+                    }
+                    self.reply(Reply::ok(id, "WHO", args).with_trailing(peer.to_string()))?
+                }
+                self.reply(Reply::ok(id, "WHO", vec![String::from("END")]))?;
+            }
+        }
+        Ok(())
+    }
 
-                                                    if let Some(title) = notifications.title {
-                                                        replies.push(protocol::reply(
-                                                            msg.id,
-                                                            true,
-                                                            "HISTORY",
-                                                            vec!["TITLE"],
-                                                            Some(&title),
-                                                        ));
-                                                    }
+    fn login(&self, id: Option<u32>, user: Option<String>, password: Option<String>) -> anyhow::Result<()> {
+        let Some(user) = user.or_else(|| self.local_user()) else {
+            self.reply(Reply::err(id, "LOGIN", ErrorCode::MissingArg))?;
+            return Ok(());
+        };
+        let logged_in = self.state.lock().unwrap().login.clone();
+        if let Some(logged_in) = logged_in {
+            self.reply(
+                Reply::err(id, "LOGIN", ErrorCode::AlreadyLoggedIn)
+                    .with_trailing(format!("You are already logged in as {}. Please reconnect.", logged_in.name)),
+            )?;
+            return Ok(());
+        }
+        match self.authenticate(&user, password.as_deref()) {
+            Some(permissions) => {
+                let login = Arc::new(Login::new(&user));
+                {
+                    let mut state = self.state.lock().unwrap();
+                    state.login = Some(login);
+                    state.permissions = permissions;
+                }
+                self.reply(Reply::ok(id, "LOGIN", vec![]).with_trailing(format!("Welcome {user}")))?;
+                debug!("user {user} logged in");
+            }
+            None => {
+                warn!("failed login for {user} from {}", self.peer);
+                self.reply(Reply::err(id, "LOGIN", ErrorCode::AuthFailed))?
+            }
+        }
+        Ok(())
+    }
 
-                                                    if !notifications.tags.is_empty() {
-                                                        replies.push(protocol::reply(
-                                                            msg.id,
-                                                            true,
-                                                            "HISTORY",
-                                                            vec!["TAGS"],
-                                                            Some(&notifications.tags.join(" ")),
-                                                        ));
-                                                    }
-                                                    if let Some(body) = notifications.body {
-                                                        for line in body.lines() {
-                                                            replies.push(protocol::reply(
-                                                                msg.id,
-                                                                true,
-                                                                "HISTORY",
-                                                                vec!["BODY"],
-                                                                Some(line),
-                                                            ));
-                                                        }
-                                                    }
-                                                }
-                                                self.write(&replies.join(""))?;
-                                            }
-                                            Err(e) => {
-                                                error!("db failure: {e}");
-                                                self.write(&protocol::reply(
-                                                    msg.id,
-                                                    false,
-                                                    "HISTORY",
-                                                    vec!["DB_FAIL"],
-                                                    Some(&format!("{e}")),
-                                                ))?
-                                            }
-                                        }
-                                    },
-                                }
-                            } else {
-                                self.write(&protocol::reply(
-                                    msg.id,
-                                    false,
-                                    "HISTORY",
-                                    vec!["NO_DB"],
-                                    None,
-                                ))?
-                            }
-                        }
-                        "WHO" => {
-                            for (login, peer, consume) in self.server.who() {
-                                    let args = if consume {
-                                        vec![login.as_ref(), "CONSUME"]
-                                    } else {
-                                        vec![login.as_ref()]
-                                    };
-                                    self.write(&protocol::reply(
-                                        msg.id,
-                                        true,
-                                        "WHO",
-                                        args,
-                                        Some(&peer.to_string()),
-                                    ))?
-                            }
-                            self.write(&protocol::reply(msg.id, true, "WHO", vec!["END"], None))?;
-                        }
-                        _ => self.write(&protocol::reply(
-                            msg.id,
-                            false,
-                            "ERR",
-                            vec!["UNKNOWN_CMD"],
-                            Some(&format!("I do not know {}", &cmd)),
-                        ))?,
+    fn start_tls(&self, id: Option<u32>) -> anyhow::Result<()> {
+        let error = if self.tls.load(Ordering::Relaxed) {
+            Some(ErrorCode::AlreadyTls)
+        } else if self.server.tls.is_none() {
+            Some(ErrorCode::NoTls)
+        } else {
+            None
+        };
+        match error {
+            Some(error) => self.reply(Reply::err(id, "STARTTLS", error))?,
+            None => {
+                let conn = rustls::ServerConnection::new(self.server.tls.clone().unwrap())?;
+                self.tls.store(true, Ordering::Relaxed);
+                {
+                    // lines sent with STARTTLS must not be run as if they came over TLS
+                    let mut inbox = self.inbox.lock().unwrap();
+                    let before = inbox.lines.len();
+                    inbox.lines.retain(Option::is_none);
+                    if inbox.lines.len() < before {
+                        warn!("{}: dropped lines sent in plain text after STARTTLS", self.peer);
                     }
+                    inbox.upgrading = true;
                 }
+                self.push(Outgoing::StartTls(Reply::ok(id, "STARTTLS", vec![]).line(), Box::new(conn)))?;
+                debug!("{} upgraded to TLS", self.peer);
             }
         }
         Ok(())
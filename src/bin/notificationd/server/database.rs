@@ -1,8 +1,163 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::mpsc;
+
 use anyhow::anyhow;
 use rusqlite::Connection;
+use rusqlite::OptionalExtension;
 use rusqlite::params;
 
+use notificationd::notifications::Icon;
 use notificationd::notifications::NotificationDetails;
+use notificationd::notifications::Urgency;
+use notificationd::protocol::ErrorCode;
+
+use crate::server::routing;
+use crate::server::routing::Login;
+
+/// Errors of database operations requested by a client
+#[derive(Debug)]
+pub enum DbError {
+    /// The server is not persistent
+    NoDb,
+    NotFound,
+    /// The notification belongs to another user
+    Forbidden,
+    Sqlite(rusqlite::Error),
+}
+
+impl DbError {
+    /// The protocol error code
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            DbError::NoDb => ErrorCode::NoDb,
+            DbError::NotFound => ErrorCode::NotFound,
+            DbError::Forbidden => ErrorCode::Forbidden,
+            DbError::Sqlite(_) => ErrorCode::DbFail,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for DbError {
+    fn from(e: rusqlite::Error) -> Self {
+        match e {
+            rusqlite::Error::QueryReturnedNoRows => DbError::NotFound,
+            e => DbError::Sqlite(e),
+        }
+    }
+}
+
+impl std::fmt::Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbError::NoDb => write!(f, "This server does not store notifications."),
+            DbError::NotFound => write!(f, "No such notification."),
+            DbError::Forbidden => write!(f, "This notification belongs to another user."),
+            DbError::Sqlite(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+enum Request {
+    Run(Box<dyn FnOnce(&mut Connection) + Send>),
+    Close(mpsc::Sender<()>),
+}
+
+/// Connections kept open, so a slow query doesn't hold up the others.
+/// Sqlite still lets only one of them write at a time.
+const CONNECTIONS: usize = 4;
+
+/// Threads owning a connection to the database each, running the queries sent to them
+#[derive(Clone)]
+pub struct Database {
+    requests: mpsc::Sender<Request>,
+}
+
+impl Database {
+    /// Start a thread for `db` and the other connections to the database at `path`
+    pub fn spawn(db: Connection, path: &Path) -> anyhow::Result<Self> {
+        let mut connections = vec![db];
+        for _ in 1..CONNECTIONS {
+            connections.push(open(path)?);
+        }
+        let (requests, receiver) = mpsc::channel::<Request>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for (n, mut db) in connections.into_iter().enumerate() {
+            let receiver = receiver.clone();
+            std::thread::Builder::new().name(format!("database {n}")).spawn(move || {
+                loop {
+                    let request = receiver.lock().unwrap().recv();
+                    match request {
+                        Ok(Request::Run(f)) => f(&mut db),
+                        Ok(Request::Close(done)) => {
+                            if let Err((_, e)) = db.close() {
+                                tracing::error!("Failed to close the database: {e}");
+                            }
+                            let _ = done.send(());
+                            return;
+                        }
+                        Err(_) => return,
+                    }
+                }
+            })?;
+        }
+        Ok(Self { requests })
+    }
+
+    /// Run `f` on a database thread and wait for its result, None once the database is closed
+    pub fn run<R: Send + 'static>(&self, f: impl FnOnce(&mut Connection) -> R + Send + 'static) -> Option<R> {
+        let (tx, rx) = mpsc::channel();
+        let f = move |db: &mut Connection| {
+            let _ = tx.send(f(db));
+        };
+        self.requests.send(Request::Run(Box::new(f))).ok()?;
+        rx.recv().ok()
+    }
+
+    /// Close the connections after the queries sent before
+    pub fn close(&self) {
+        let (tx, rx) = mpsc::channel();
+        // every thread closes its connection and stops at the first of these
+        let sent = (0..CONNECTIONS).filter(|_| self.requests.send(Request::Close(tx.clone())).is_ok()).count();
+        drop(tx);
+        for _ in 0..sent {
+            let _ = rx.recv();
+        }
+    }
+}
+
+/// Open the database at `path` in WAL mode, where reading doesn't wait for writes
+pub fn open(path: &Path) -> rusqlite::Result<Connection> {
+    let db = Connection::open(path)?;
+    let mode: String = db.pragma_update_and_check(None, "journal_mode", "wal", |row| row.get(0))?;
+    if !mode.eq_ignore_ascii_case("wal") {
+        tracing::warn!("Database {} uses {mode} journaling instead of WAL", path.display());
+    }
+    // with WAL this may lose the last commits on power loss, but not corrupt the database
+    db.pragma_update(None, "synchronous", "NORMAL")?;
+    Ok(db)
+}
+
+/// Schema changes applied on top of the initial table, indexed by `user_version`
+const MIGRATIONS: &[&str] = &[
+    "ALTER TABLE notifications ADD COLUMN recipients TEXT",
+    "ALTER TABLE notifications ADD COLUMN icon TEXT;
+    ALTER TABLE notifications ADD COLUMN icon_png BLOB;",
+    "ALTER TABLE notifications ADD COLUMN quiet INTEGER NOT NULL DEFAULT 0",
+    "ALTER TABLE notifications ADD COLUMN urgency INTEGER NOT NULL DEFAULT 1",
+    "ALTER TABLE notifications ADD COLUMN actions TEXT;
+    ALTER TABLE notifications ADD COLUMN callback TEXT;",
+    "CREATE TABLE dismissals (
+        id INTEGER NOT NULL,
+        user TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        PRIMARY KEY (id, user)
+    );
+    CREATE TRIGGER delete_dismissals AFTER DELETE ON notifications BEGIN
+        DELETE FROM dismissals WHERE id = old.id;
+    END;",
+];
 
 pub fn setup_database(db: &mut Connection) -> rusqlite::Result<usize> {
     let n = db.execute(
@@ -16,9 +171,65 @@ pub fn setup_database(db: &mut Connection) -> rusqlite::Result<usize> {
         )",
         (),
     )?;
+    migrate(db)?;
     Ok(n)
 }
 
+fn migrate(db: &mut Connection) -> rusqlite::Result<()> {
+    let version: usize = db.query_row("PRAGMA user_version", (), |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = db.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+        tracing::info!("Migrated database to version {}", i + 1);
+    }
+    Ok(())
+}
+
+/// The id after the highest one ever handed out.
+/// The sequence of the table also counts deleted notifications and those passed to [skip_id],
+/// so ids are not reused after a restart.
+pub fn next_id(db: &Connection) -> rusqlite::Result<usize> {
+    let seq: Option<usize> = db
+        .prepare_cached("SELECT seq FROM sqlite_sequence WHERE name = 'notifications'")?
+        .query_row((), |row| row.get(0))
+        .optional()?;
+    Ok(seq.map_or(1, |id| id + 1))
+}
+
+/// Advance the sequence of ids past a notification that was not stored
+pub fn skip_id(db: &Connection, id: usize) -> rusqlite::Result<()> {
+    let n = db
+        .prepare_cached("UPDATE sqlite_sequence SET seq = max(seq, ?1) WHERE name = 'notifications'")?
+        .execute([id])?;
+    if n == 0 {
+        db.prepare_cached("INSERT INTO sqlite_sequence (name, seq) VALUES ('notifications', ?1)")?.execute([id])?;
+    }
+    Ok(())
+}
+
+/// Remember that `user` dismissed a notification
+pub fn dismiss(db: &Connection, id: usize, user: &str) -> rusqlite::Result<usize> {
+    db.prepare_cached("INSERT OR IGNORE INTO dismissals (id, user, timestamp) VALUES (?1, ?2, unixepoch())")?
+        .execute(params![id, user])
+}
+
+/// Split a space separated column into its words
+fn split_words(column: Option<String>) -> Vec<String> {
+    column
+        .unwrap_or_default()
+        .split(" ")
+        .filter_map(|s| {
+            if !s.is_empty() {
+                Some(String::from(s))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
 pub trait NotificationDetailsDatabaseExt
 where
     Self: Sized,
@@ -26,7 +237,83 @@ where
     type Key;
     fn save(&self, db: &mut Connection) -> anyhow::Result<usize>;
     fn load(db: &mut Connection, key: Self::Key) -> rusqlite::Result<Self>;
-    fn load_all(db: &mut Connection, limit: Option<u32>) -> rusqlite::Result<Vec<Self>>;
+    /// Load the last `limit` notifications `login` sent or is a recipient of
+    fn load_visible(db: &mut Connection, login: &Login, limit: Option<u32>) -> rusqlite::Result<Vec<Self>>;
+    /// Load the notifications with an id higher than `offset` that `login` should catch up on:
+    /// those addressed to it that are not quiet and that its user did not dismiss
+    fn load_missed(db: &mut Connection, login: &Login, offset: Self::Key) -> rusqlite::Result<Vec<Self>>;
+    fn delete(db: &mut Connection, key: Self::Key) -> rusqlite::Result<usize>;
+    /// Delete all notifications matching a filter
+    fn purge(db: &mut Connection, filter: &Purge) -> rusqlite::Result<usize>;
+}
+
+/// Filter for deleting notifications in bulk.
+/// Only notifications matching all set fields are deleted.
+#[derive(Debug, Default, Clone)]
+pub struct Purge {
+    /// Sent by this user or any of its `user@host` logins
+    pub user: Option<String>,
+    pub tag: Option<String>,
+    /// Older than this many seconds
+    pub older_than: Option<u64>,
+}
+
+/// Join actions into a column with a `key label` line per action
+fn join_actions(actions: &[(String, String)]) -> Option<String> {
+    if actions.is_empty() {
+        return None;
+    }
+    Some(actions.iter().map(|(key, label)| format!("{key} {label}")).collect::<Vec<_>>().join("\n"))
+}
+
+fn split_actions(column: Option<String>) -> Vec<(String, String)> {
+    column
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .map(|(key, label)| (key.to_owned(), label.to_owned()))
+        .collect()
+}
+
+/// Columns read by [notification_from_row]
+const COLUMNS: &str = "id, user, title, body, tags, datetime(timestamp, 'unixepoch') as timestamp, recipients, icon, icon_png, quiet, urgency, actions, callback";
+
+/// Sent by the user `?2` or any of its `user@host` logins
+const SENT_BY: &str = "(user = ?2 OR substr(user, 1, length(?2) + 1) = ?2 || '@')";
+
+/// Addressed to a login of the user `?2`, that matches the recipients in the JSON array `?3`.
+/// Without recipients a notification is addressed to its sender.
+const ADDRESSED_TO: &str = "CASE WHEN coalesce(recipients, '') = ''
+    THEN (user = ?2 OR substr(user, 1, length(?2) + 1) = ?2 || '@')
+    ELSE EXISTS (SELECT 1 FROM json_each(?3) WHERE instr(' ' || recipients || ' ', ' ' || value || ' ') > 0)
+END";
+
+/// The parameters `?2` and `?3` of [SENT_BY] and [ADDRESSED_TO]
+fn login_params(login: &Login) -> (&str, String) {
+    let recipients = serde_json::to_string(&login.recipients()).unwrap_or_default();
+    (routing::base_user(&login.name), recipients)
+}
+
+fn notification_from_row(row: &rusqlite::Row) -> rusqlite::Result<NotificationDetails> {
+    Ok(NotificationDetails {
+        id: row.get(0)?,
+        user: row.get(1)?,
+        title: row.get(2)?,
+        body: row.get(3)?,
+        tags: split_words(row.get(4)?),
+        recipients: split_words(row.get(6)?),
+        icon: match (row.get(7)?, row.get(8)?) {
+            (_, Some(data)) => Some(Icon::Png(data)),
+            (Some(name), None) => Some(Icon::Name(name)),
+            (None, None) => None,
+        },
+        quiet: row.get(9)?,
+        urgency: Urgency::from_level(row.get(10)?).unwrap_or_default(),
+        actions: split_actions(row.get(11)?),
+        callback: row.get(12)?,
+        ephemeral: false,
+        timestamp: row.get(5)?,
+    })
 }
 
 impl NotificationDetailsDatabaseExt for NotificationDetails {
@@ -37,45 +324,195 @@ impl NotificationDetailsDatabaseExt for NotificationDetails {
             .user
             .as_ref()
             .ok_or(anyhow!("No user on notification"))?;
-        Ok(db.execute(
-            "INSERT INTO notifications (user, title, body, tags, timestamp)
-            VALUES (?1, ?2, ?3, ?4, unixepoch())",
-            params![user, self.title, self.body, self.tags.join(" ")],
-        )?)
+        let (icon, icon_png) = match &self.icon {
+            Some(Icon::Name(name)) => (Some(name), None),
+            Some(Icon::Png(data)) => (None, Some(data)),
+            None => (None, None),
+        };
+        let mut stmt = db.prepare_cached(
+            "INSERT INTO notifications (id, user, title, body, tags, recipients, icon, icon_png, quiet, urgency, actions, callback, timestamp)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, unixepoch())",
+        )?;
+        Ok(stmt.execute(params![
+                self.id,
+                user,
+                self.title,
+                self.body,
+                self.tags.join(" "),
+                self.recipients.join(" "),
+                icon,
+                icon_png,
+                self.quiet,
+                self.urgency.level(),
+                join_actions(&self.actions),
+                self.callback,
+            ])?)
     }
 
     fn load(db: &mut Connection, key: Self::Key) -> rusqlite::Result<Self> {
-        todo!()
+        db.prepare_cached(&format!("SELECT {COLUMNS} FROM notifications WHERE id = ?1"))?
+            .query_row([key], notification_from_row)
     }
 
-    fn load_all(db: &mut Connection, limit: Option<u32>) -> rusqlite::Result<Vec<Self>> {
-        let mut stmt = db.prepare(
+    fn load_visible(db: &mut Connection, login: &Login, limit: Option<u32>) -> rusqlite::Result<Vec<Self>> {
+        let mut stmt = db.prepare_cached(&format!(
             "SELECT * FROM (
-                SELECT id, user, title, body, tags, datetime(timestamp, 'unixepoch') as timestamp
-                FROM notifications ORDER BY id DESC LIMIT ?1
+                SELECT {COLUMNS}
+                FROM notifications WHERE {SENT_BY} OR {ADDRESSED_TO}
+                ORDER BY id DESC LIMIT ?1
             ) ORDER BY id ASC
-        ")?;
-        stmt.query_map([limit.map_or(-1, |l| l as i32)], |row| {
-            Ok(NotificationDetails {
-                id: row.get(0)?,
-                user: row.get(1)?,
-                title: row.get(2)?,
-                body: row.get(3)?,
-                tags: row
-                    .get::<usize, String>(4)?
-                    .split(" ")
-                    .filter_map(|s| {
-                        if !s.is_empty() {
-                            Some(String::from(s))
-                        } else {
-                            None
-                        }
-                    })
-                    .collect(),
-                timestamp: row.get(5)?,
-            })
-        })?
-        .into_iter()
+        "))?;
+        let (user, recipients) = login_params(login);
+        stmt.query_map(params![limit.map_or(-1, i64::from), user, recipients], notification_from_row)?
         .collect()
     }
+
+    fn load_missed(db: &mut Connection, login: &Login, offset: Self::Key) -> rusqlite::Result<Vec<Self>> {
+        let mut stmt = db.prepare_cached(&format!(
+            "SELECT {COLUMNS} FROM notifications
+            WHERE id > ?1 AND quiet = 0 AND {ADDRESSED_TO}
+                AND id NOT IN (SELECT id FROM dismissals WHERE user = ?2)
+            ORDER BY id ASC"
+        ))?;
+        let (user, recipients) = login_params(login);
+        stmt.query_map(params![offset, user, recipients], notification_from_row)?
+        .collect()
+    }
+
+    fn delete(db: &mut Connection, key: Self::Key) -> rusqlite::Result<usize> {
+        db.prepare_cached("DELETE FROM notifications WHERE id = ?1")?.execute([key])
+    }
+
+    fn purge(db: &mut Connection, filter: &Purge) -> rusqlite::Result<usize> {
+        db.prepare_cached(
+            "DELETE FROM notifications WHERE
+                (?1 IS NULL OR user = ?1 OR substr(user, 1, length(?1) + 1) = ?1 || '@')
+                AND (?2 IS NULL OR instr(' ' || tags || ' ', ' ' || ?2 || ' ') > 0)
+                AND (?3 IS NULL OR timestamp < unixepoch() - ?3)",
+        )?
+        .execute(params![filter.user, filter.tag, filter.older_than])
+    }
+}
+
+#[test]
+fn purge_by_user_family() {
+    let mut db = Connection::open_in_memory().unwrap();
+    setup_database(&mut db).unwrap();
+    for (id, user, tags) in [(1, "rein@laptop", "ci"), (2, "rein", "ci deploy"), (3, "reinier", "ci")] {
+        let mut details = NotificationDetails::new();
+        details.id = Some(id);
+        details.user = Some(String::from(user));
+        details.tags = tags.split(" ").map(String::from).collect();
+        details.save(&mut db).unwrap();
+    }
+    let filter = Purge {
+        user: Some(String::from("rein")),
+        tag: Some(String::from("deploy")),
+        ..Default::default()
+    };
+    assert_eq!(NotificationDetails::purge(&mut db, &filter).unwrap(), 1);
+    let filter = Purge {
+        user: Some(String::from("rein")),
+        ..Default::default()
+    };
+    assert_eq!(NotificationDetails::purge(&mut db, &filter).unwrap(), 1);
+    assert_eq!(NotificationDetails::load(&mut db, 3).unwrap().user.as_deref(), Some("reinier"));
+}
+
+#[test]
+fn dismissals_are_deleted_with_notification() {
+    let mut db = Connection::open_in_memory().unwrap();
+    setup_database(&mut db).unwrap();
+    for id in [1, 2] {
+        let mut details = NotificationDetails::new();
+        details.id = Some(id);
+        details.user = Some(String::from("bot"));
+        details.save(&mut db).unwrap();
+        dismiss(&db, id, "rein").unwrap();
+    }
+    let dismissed = |db: &Connection| -> Vec<usize> {
+        let mut stmt = db.prepare("SELECT id FROM dismissals WHERE user = 'rein' ORDER BY id").unwrap();
+        stmt.query_map((), |row| row.get(0)).unwrap().map(Result::unwrap).collect()
+    };
+    assert_eq!(dismissed(&db), [1, 2]);
+    NotificationDetails::delete(&mut db, 1).unwrap();
+    assert_eq!(dismissed(&db), [2]);
+}
+
+#[test]
+fn visible_and_missed_notifications() {
+    let mut db = Connection::open_in_memory().unwrap();
+    setup_database(&mut db).unwrap();
+    let sent = [
+        (1, "bot", "", false),
+        (2, "bot", "rein", false),
+        (3, "rein@desktop", "", false),
+        (4, "bot", "@wheel", true),
+        (5, "bot", "rein@desktop", false),
+        (6, "bot", "other rein@laptop", false),
+        (7, "bot", "reinier", false),
+    ];
+    for (id, user, recipients, quiet) in sent {
+        let mut details = NotificationDetails::new();
+        details.id = Some(id);
+        details.user = Some(String::from(user));
+        details.recipients = recipients.split_whitespace().map(String::from).collect();
+        details.quiet = quiet;
+        details.save(&mut db).unwrap();
+    }
+    dismiss(&db, 6, "rein").unwrap();
+    let login = Login { name: String::from("rein@laptop"), groups: vec![String::from("wheel")] };
+    let ids = |notifications: Vec<NotificationDetails>| -> Vec<usize> { notifications.iter().filter_map(|n| n.id).collect() };
+
+    assert_eq!(ids(NotificationDetails::load_visible(&mut db, &login, None).unwrap()), [2, 3, 4, 6]);
+    assert_eq!(ids(NotificationDetails::load_visible(&mut db, &login, Some(2)).unwrap()), [4, 6]);
+    let missed = NotificationDetails::load_missed(&mut db, &login, 0).unwrap();
+    assert!(missed.iter().all(|n| routing::is_addressed_to(n, &login)));
+    assert_eq!(ids(missed), [2, 3]);
+    assert_eq!(ids(NotificationDetails::load_missed(&mut db, &login, 2).unwrap()), [3]);
+}
+
+#[test]
+fn ids_are_not_reused() {
+    let mut db = Connection::open_in_memory().unwrap();
+    setup_database(&mut db).unwrap();
+    assert_eq!(next_id(&db).unwrap(), 1);
+    skip_id(&db, 1).unwrap();
+    for id in [2, 3] {
+        let mut details = NotificationDetails::new();
+        details.id = Some(id);
+        details.user = Some(String::from("bot"));
+        details.save(&mut db).unwrap();
+    }
+    skip_id(&db, 4).unwrap();
+    assert_eq!(next_id(&db).unwrap(), 5);
+    NotificationDetails::purge(&mut db, &Purge::default()).unwrap();
+    assert_eq!(next_id(&db).unwrap(), 5);
+}
+
+#[test]
+fn connections_share_the_database() {
+    let path = std::env::temp_dir().join(format!("notificationd-test-{}.sqlite3", std::process::id()));
+    let mut db = open(&path).unwrap();
+    setup_database(&mut db).unwrap();
+    let database = Database::spawn(db, &path).unwrap();
+    database
+        .run(|db| {
+            let mut details = NotificationDetails::new();
+            details.id = Some(1);
+            details.user = Some(String::from("bot"));
+            details.save(db).unwrap();
+        })
+        .unwrap();
+    // written by one connection, read by all of them
+    for _ in 0..CONNECTIONS * 2 {
+        let mode: String = database.run(|db| db.pragma_query_value(None, "journal_mode", |row| row.get(0))).unwrap().unwrap();
+        assert_eq!(mode, "wal");
+        assert_eq!(database.run(|db| next_id(db)).unwrap().unwrap(), 2);
+    }
+    database.close();
+    assert!(database.run(|_| ()).is_none());
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+    }
 }
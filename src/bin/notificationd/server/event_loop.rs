@@ -0,0 +1,475 @@
+//! the event loop doing the I/O of every connection
+//!
+//! A single thread accepts connections, reads their lines and writes what is queued for them,
+//! driving rustls itself for TLS. Idle connections only cost their buffers.
+//! Commands run on the worker pool, which queues the replies and wakes the loop.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::Shutdown;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::Ordering;
+
+use mio::Events;
+use mio::Interest;
+use mio::Poll;
+use mio::Token;
+use mio::Waker;
+use mio::net::TcpListener;
+use mio::net::TcpStream;
+use mio::net::UnixListener;
+use mio::net::UnixStream;
+use nix::errno::Errno;
+use tracing::debug;
+use tracing::error;
+use tracing::trace;
+use tracing::warn;
+
+use crate::server::Peer;
+use crate::server::ServerHandle;
+use crate::server::UNIX_COUNTER;
+use crate::server::client::ClientHandle;
+use crate::server::queue::Outgoing;
+
+const WAKER: Token = Token(usize::MAX);
+
+/// Bytes waiting to be written before no more messages are taken from the queue
+const MAX_OUTGOING: usize = 64 * 1024;
+
+/// Longest line a client may send, longer ones close the connection
+const MAX_LINE: usize = 64 * 1024;
+
+/// Connections the event loop should look at, because something was queued for them
+pub struct Wakeups {
+    ids: Mutex<HashSet<usize>>,
+    waker: Waker,
+}
+
+impl Wakeups {
+    pub fn wake(&self, id: usize) {
+        let mut ids = self.ids.lock().unwrap();
+        // all ids are taken at once, so only the first needs to wake the loop
+        if ids.insert(id)
+            && ids.len() == 1
+            && let Err(e) = self.waker.wake()
+        {
+            error!("failed to wake the event loop: {e}");
+        }
+    }
+
+    fn take(&self) -> HashSet<usize> {
+        std::mem::take(&mut *self.ids.lock().unwrap())
+    }
+}
+
+pub enum Listener {
+    Tcp {
+        listener: TcpListener,
+        /// Connections start with a TLS handshake
+        implicit_tls: bool,
+    },
+    /// Local connections, which log in as the user of the connecting process
+    Unix(UnixListener),
+}
+
+enum Socket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Socket {
+    fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Socket::Tcp(sock) => sock.shutdown(Shutdown::Both),
+            Socket::Unix(sock) => sock.shutdown(Shutdown::Both),
+        }
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(sock) => sock.read(buf),
+            Socket::Unix(sock) => sock.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(sock) => sock.write(buf),
+            Socket::Unix(sock) => sock.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Connection {
+    socket: Socket,
+    tls: Option<rustls::ServerConnection>,
+    handle: ClientHandle,
+    /// Read, but not a complete line yet
+    incoming: Vec<u8>,
+    /// Waiting for the socket to accept it
+    outgoing: Vec<u8>,
+    /// The input ended
+    eof: bool,
+    /// Reading stopped until the worker catches up
+    paused: bool,
+    /// Everything queued before a close is written, notified once the connection is closed
+    closing: Option<std::sync::mpsc::Sender<()>>,
+}
+
+impl Connection {
+    /// Read what is available and hand the lines to the worker
+    fn receive(&mut self) {
+        let mut buf = [0; 16 * 1024];
+        while !self.eof {
+            self.paused = self.handle.paused();
+            if self.paused {
+                return;
+            }
+            match self.read(&mut buf) {
+                Ok(0) => self.end(),
+                Ok(n) => {
+                    self.incoming.extend_from_slice(&buf[..n]);
+                    if let Err(e) = self.lines() {
+                        warn!("{}: {e}", self.handle.peer);
+                        self.incoming.clear();
+                        self.end();
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    warn!("{}: {e}", self.handle.peer);
+                    self.end();
+                }
+            }
+        }
+    }
+
+    /// Stop reading, the connection is closed once the lines read before are handled
+    fn end(&mut self) {
+        self.eof = true;
+        // the last line may lack a newline
+        if !self.incoming.is_empty() {
+            self.incoming.push(b'\n');
+            let _ = self.lines();
+        }
+        self.handle.receive(None);
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(tls) = &mut self.tls else {
+            return self.socket.read(buf);
+        };
+        loop {
+            match tls.reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                res => return res,
+            }
+            if tls.read_tls(&mut self.socket)? == 0 {
+                return Ok(0);
+            }
+            let processed = tls.process_new_packets();
+            // handshake messages, or the alert for an error
+            while tls.wants_write() {
+                tls.write_tls(&mut self.outgoing)?;
+            }
+            processed.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+    }
+
+    /// Hand the complete lines to the worker, failing on lines that are invalid or too long
+    fn lines(&mut self) -> io::Result<()> {
+        let too_long = || io::Error::new(io::ErrorKind::InvalidData, format!("line longer than {MAX_LINE} bytes"));
+        let mut start = 0;
+        while let Some(n) = self.incoming[start..].iter().position(|b| *b == b'\n') {
+            let line = &self.incoming[start..start + n];
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if line.len() > MAX_LINE {
+                return Err(too_long());
+            }
+            let line = String::from_utf8(line.to_vec()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            self.handle.receive(Some(line));
+            start += n + 1;
+        }
+        self.incoming.drain(..start);
+        if self.incoming.len() > MAX_LINE {
+            return Err(too_long());
+        }
+        Ok(())
+    }
+
+    /// Take messages from the queue and write them until the socket is full
+    fn flush(&mut self) -> io::Result<()> {
+        loop {
+            self.fill()?;
+            if self.outgoing.is_empty() {
+                return Ok(());
+            }
+            match self.socket.write(&self.outgoing) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => drop(self.outgoing.drain(..n)),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        while self.closing.is_none() && self.outgoing.len() < MAX_OUTGOING {
+            match self.handle.queue.pop() {
+                Some(Outgoing::Message(msg) | Outgoing::Notification(_, msg)) => self.encode(msg.as_bytes())?,
+                Some(Outgoing::StartTls(reply, conn)) => {
+                    self.encode(reply.as_bytes())?;
+                    self.tls = Some(*conn);
+                    // the rest of a line sent with STARTTLS
+                    self.incoming.clear();
+                    self.handle.upgraded();
+                }
+                Some(Outgoing::Close(done)) => self.closing = Some(done),
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn encode(&mut self, msg: &[u8]) -> io::Result<()> {
+        match &mut self.tls {
+            None => self.outgoing.extend_from_slice(msg),
+            Some(tls) => {
+                tls.writer().write_all(msg)?;
+                while tls.wants_write() {
+                    tls.write_tls(&mut self.outgoing)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Everything that will be written is
+    fn done(&self) -> bool {
+        self.handle.queue.overflowed()
+            || (self.outgoing.is_empty() && (self.closing.is_some() || self.handle.queue.is_done()))
+    }
+
+    fn close(mut self) {
+        if !self.handle.queue.overflowed()
+            && let Some(tls) = &mut self.tls
+        {
+            tls.send_close_notify();
+            let _ = tls.write_tls(&mut self.outgoing);
+            let _ = self.socket.write(&self.outgoing);
+        }
+        let _ = self.socket.shutdown();
+        self.handle.remove();
+        if let Some(done) = self.closing {
+            let _ = done.send(());
+        }
+        trace!("closed connection {}", self.handle.peer);
+    }
+}
+
+pub struct EventLoop {
+    poll: Poll,
+    listeners: Vec<Listener>,
+    connections: HashMap<usize, Connection>,
+    server: ServerHandle,
+    wakeups: Arc<Wakeups>,
+    /// Tokens below are those of the listeners
+    next_id: usize,
+}
+
+impl EventLoop {
+    pub fn new(mut listeners: Vec<Listener>, server: ServerHandle) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        for (i, listener) in listeners.iter_mut().enumerate() {
+            match listener {
+                Listener::Tcp { listener, .. } => poll.registry().register(listener, Token(i), Interest::READABLE)?,
+                Listener::Unix(listener) => poll.registry().register(listener, Token(i), Interest::READABLE)?,
+            }
+        }
+        let wakeups = Arc::new(Wakeups {
+            ids: Mutex::new(HashSet::new()),
+            waker: Waker::new(poll.registry(), WAKER)?,
+        });
+        Ok(Self {
+            poll,
+            next_id: listeners.len(),
+            listeners,
+            connections: HashMap::new(),
+            server,
+            wakeups,
+        })
+    }
+
+    /// Run until polling fails
+    pub fn run(mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(1024);
+        loop {
+            match self.poll.poll(&mut events, None) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                res => res?,
+            }
+            for event in &events {
+                match event.token() {
+                    WAKER => (),
+                    Token(i) if i < self.listeners.len() => self.accept(i),
+                    Token(id) => self.ready(id, event.is_readable() || event.is_read_closed()),
+                }
+            }
+            for id in self.wakeups.take() {
+                self.ready(id, false);
+            }
+        }
+    }
+
+    fn accept(&mut self, i: usize) {
+        loop {
+            let accepted = match &self.listeners[i] {
+                Listener::Tcp { listener, implicit_tls } => listener
+                    .accept()
+                    .map(|(sock, addr)| (Socket::Tcp(sock), Peer::Tcp(addr), *implicit_tls)),
+                Listener::Unix(listener) => listener.accept().and_then(|(sock, _)| {
+                    let creds = nix::sys::socket::getsockopt(&sock, nix::sys::socket::sockopt::PeerCredentials)?;
+                    let peer = Peer::Unix {
+                        id: UNIX_COUNTER.fetch_add(1, Ordering::Relaxed),
+                        uid: creds.uid(),
+                        pid: creds.pid(),
+                    };
+                    Ok((Socket::Unix(sock), peer, false))
+                }),
+            };
+            let (mut socket, peer, implicit_tls) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    error!("failed to accept a connection: {e}");
+                    // these fail again right away, the next connection tries again
+                    let errno = e.raw_os_error().map(Errno::from_raw);
+                    if matches!(errno, Some(Errno::EMFILE | Errno::ENFILE | Errno::ENOBUFS | Errno::ENOMEM)) {
+                        return;
+                    }
+                    continue;
+                }
+            };
+            if self.server.state.lock().unwrap().shutting_down {
+                continue;
+            }
+            let tls = if implicit_tls {
+                let config = self.server.tls.clone().expect("TLS listener without TLS config");
+                match rustls::ServerConnection::new(config) {
+                    Ok(conn) => Some(conn),
+                    Err(e) => {
+                        error!("failed to start TLS for {peer}: {e}");
+                        continue;
+                    }
+                }
+            } else {
+                None
+            };
+
+            let id = self.next_id;
+            self.next_id += 1;
+            let interest = Interest::READABLE | Interest::WRITABLE;
+            let registered = match &mut socket {
+                Socket::Tcp(sock) => self.poll.registry().register(sock, Token(id), interest),
+                Socket::Unix(sock) => self.poll.registry().register(sock, Token(id), interest),
+            };
+            if let Err(e) = registered {
+                error!("failed to register {peer}: {e}");
+                continue;
+            }
+            let handle = ClientHandle::new(id, peer, tls.is_some(), self.server.clone(), self.wakeups.clone());
+            self.server.clients.insert(handle.clone());
+            let connection = Connection {
+                socket,
+                tls,
+                handle,
+                incoming: vec![],
+                outgoing: vec![],
+                eof: false,
+                paused: false,
+                closing: None,
+            };
+            self.connections.insert(id, connection);
+            // the socket may already be readable
+            self.ready(id, true);
+        }
+    }
+
+    /// Read from and write to a connection, closing it when it is done
+    fn ready(&mut self, id: usize, readable: bool) {
+        let Some(connection) = self.connections.get_mut(&id) else {
+            return;
+        };
+        if readable || connection.paused {
+            connection.receive();
+        }
+        match connection.flush() {
+            Ok(()) if connection.done() => (),
+            Ok(()) => {
+                if let Some(from) = connection.handle.queue.start_replay() {
+                    let handle = connection.handle.clone();
+                    self.server.workers.spawn(move || handle.replay(from));
+                }
+                return;
+            }
+            Err(e) => debug!("failed to write to {}: {e}", connection.handle.peer),
+        }
+        if let Some(connection) = self.connections.remove(&id) {
+            connection.close();
+        }
+    }
+}
+
+#[test]
+fn no_plain_text_after_starttls() {
+    use std::io::BufRead;
+    use std::io::BufReader;
+
+    let tls = crate::tls::server_config(&crate::tls::ServerTlsArgs {
+        tls_cert: Some(concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/cert.pem").into()),
+        tls_key: Some(concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/key.pem").into()),
+        ..Default::default()
+    })
+    .unwrap();
+    let server = ServerHandle::new(String::new(), tls, super::ServerState::new(), None).unwrap();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let listener = Listener::Tcp { listener: TcpListener::from_std(listener), implicit_tls: false };
+    let event_loop = EventLoop::new(vec![listener], server).unwrap();
+    std::thread::spawn(move || event_loop.run());
+
+    let mut stream = crate::tls::Stream::plain(std::net::TcpStream::connect(address).unwrap());
+    stream.write_all(b"STARTTLS\r\nLOGIN mallory\r\n").unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert!(line.starts_with("+STARTTLS"), "{line}");
+
+    let config = crate::tls::client_config(&crate::tls::ClientTlsArgs {
+        tls_fingerprint: Some(String::from(
+            "A4:11:91:DC:1B:2A:39:D5:EB:14:42:8A:39:DE:7E:E9:6E:F1:F7:43:27:A8:F6:92:1D:31:9A:EB:F5:1A:D1:14",
+        )),
+        ..Default::default()
+    })
+    .unwrap();
+    stream.upgrade(crate::tls::client_connection(config, &format!("localhost:{}", address.port())).unwrap());
+    stream.write_all(b"LOGIN alice\r\n").unwrap();
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    assert!(line.starts_with("+LOGIN") && line.contains("alice"), "{line}");
+}
@@ -0,0 +1,57 @@
+//! threads running the commands of connections
+//!
+//! Commands may wait on the database or on hashing a password,
+//! which must not hold up the event loop.
+
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+pub struct Pool {
+    jobs: mpsc::Sender<Job>,
+    /// Jobs spawned that have not finished yet
+    pending: Arc<AtomicUsize>,
+    capacity: usize,
+}
+
+impl Pool {
+    pub fn new(name: &str, threads: usize) -> std::io::Result<Self> {
+        Self::bounded(name, threads, usize::MAX)
+    }
+
+    /// A pool refusing jobs while `capacity` of them are pending
+    pub fn bounded(name: &str, threads: usize, capacity: usize) -> std::io::Result<Self> {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let pending = Arc::new(AtomicUsize::new(0));
+        for n in 0..threads {
+            let receiver = receiver.clone();
+            let pending = pending.clone();
+            std::thread::Builder::new().name(format!("{name} {n}")).spawn(move || {
+                loop {
+                    // the lock is only held while waiting, not while running the job
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => return,
+                    }
+                    pending.fetch_sub(1, Ordering::Relaxed);
+                }
+            })?;
+        }
+        Ok(Self { jobs, pending, capacity })
+    }
+
+    /// Run `job` on one of the threads, returning false if the pool is full
+    pub fn spawn(&self, job: impl FnOnce() + Send + 'static) -> bool {
+        if self.pending.fetch_add(1, Ordering::Relaxed) >= self.capacity {
+            self.pending.fetch_sub(1, Ordering::Relaxed);
+            return false;
+        }
+        self.jobs.send(Box::new(job)).is_ok()
+    }
+}
@@ -0,0 +1,305 @@
+//! bounded queues of messages waiting to be written to a connection
+//!
+//! A consumer that doesn't read fast enough fills its queue,
+//! what happens then is decided by the [SlowConsumers] policy.
+//! Only notifications are ever dropped, a connection that has no room for a reply is closed.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+/// What happens when the queue of a connection is full
+#[derive(serde_derive::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SlowConsumers {
+    /// Drop the oldest notification to make room, closing the connection if there is none
+    #[default]
+    DropOldest,
+    /// Close the connection, the client can catch up with SINCE after reconnecting
+    Disconnect,
+    /// Stop queueing notifications and replay them from the database once the queue is empty,
+    /// closing the connection if a reply doesn't fit
+    Spill,
+}
+
+impl std::fmt::Display for SlowConsumers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SlowConsumers::DropOldest => "drop-oldest",
+            SlowConsumers::Disconnect => "disconnect",
+            SlowConsumers::Spill => "spill",
+        })
+    }
+}
+
+/// What the event loop is asked to write
+pub enum Outgoing {
+    Message(String),
+    /// A relayed notification, which may be replayed from the database instead
+    Notification(usize, String),
+    /// Write a last plain text reply, then wrap the connection in TLS
+    StartTls(String, Box<rustls::ServerConnection>),
+    /// Write everything queued before, then close the connection and report back
+    Close(std::sync::mpsc::Sender<()>),
+}
+
+/// Counters over all queues of a server
+#[derive(Default)]
+pub struct QueueStats {
+    pub dropped: AtomicU64,
+    pub disconnected: AtomicU64,
+}
+
+/// Returned when writing to a closed connection
+#[derive(Debug)]
+pub struct Closed {
+    /// Closed by this message, because the queue was full
+    pub overflowed: bool,
+}
+
+impl std::fmt::Display for Closed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "connection closed")
+    }
+}
+
+impl std::error::Error for Closed {}
+
+pub struct Queue {
+    inner: Mutex<Inner>,
+    capacity: usize,
+    policy: SlowConsumers,
+    stats: Arc<QueueStats>,
+}
+
+struct Inner {
+    messages: VecDeque<Outgoing>,
+    /// Messages dropped, or left for replay
+    dropped: u64,
+    closed: bool,
+    /// Closed because it was full
+    overflowed: bool,
+    /// The first notification that was left for replay
+    spilled_from: Option<usize>,
+    /// The first notification that was left for replay while the others were looked up
+    missed: Option<usize>,
+    /// The notifications left for replay are being looked up
+    replaying: bool,
+}
+
+impl Queue {
+    pub fn new(capacity: usize, policy: SlowConsumers, stats: Arc<QueueStats>) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                messages: VecDeque::new(),
+                dropped: 0,
+                closed: false,
+                overflowed: false,
+                spilled_from: None,
+                missed: None,
+                replaying: false,
+            }),
+            capacity,
+            policy,
+            stats,
+        }
+    }
+
+    pub fn push(&self, msg: Outgoing) -> Result<(), Closed> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.closed {
+            return Err(Closed { overflowed: false });
+        }
+        let full = inner.messages.len() >= self.capacity;
+        let notification = matches!(msg, Outgoing::Notification(..));
+        match (&msg, self.policy) {
+            // always room to close
+            (Outgoing::Close(_) | Outgoing::StartTls(..), _) => (),
+            // later notifications are replayed too, so they stay in order
+            (Outgoing::Notification(id, _), SlowConsumers::Spill) if inner.spilled_from.is_some() => {
+                self.spill(&mut inner, *id);
+                return Ok(());
+            }
+            _ if !full => (),
+            (Outgoing::Notification(id, _), SlowConsumers::Spill) => {
+                self.spill(&mut inner, *id);
+                return Ok(());
+            }
+            // only notifications are dropped, replies have to arrive
+            (_, SlowConsumers::DropOldest) => {
+                let oldest = inner.messages.iter().position(|m| matches!(m, Outgoing::Notification(..)));
+                match oldest {
+                    Some(i) => {
+                        inner.messages.remove(i);
+                    }
+                    None if notification => {
+                        self.drop_message(&mut inner);
+                        return Ok(());
+                    }
+                    None => return Err(self.overflow(&mut inner)),
+                }
+                self.drop_message(&mut inner);
+            }
+            // the newest notification is replayed after the reply instead
+            (_, SlowConsumers::Spill) => {
+                let newest = inner.messages.iter().rposition(|m| matches!(m, Outgoing::Notification(..)));
+                match newest.and_then(|i| inner.messages.remove(i)) {
+                    Some(Outgoing::Notification(id, _)) => self.spill(&mut inner, id),
+                    _ => return Err(self.overflow(&mut inner)),
+                }
+            }
+            (_, SlowConsumers::Disconnect) => return Err(self.overflow(&mut inner)),
+        }
+        inner.messages.push_back(msg);
+        Ok(())
+    }
+
+    /// Leave the notification `id` for replay
+    fn spill(&self, inner: &mut Inner, id: usize) {
+        if inner.replaying {
+            inner.missed = Some(inner.missed.map_or(id, |missed| missed.min(id)));
+        } else {
+            inner.spilled_from = Some(inner.spilled_from.map_or(id, |spilled| spilled.min(id)));
+        }
+        self.drop_message(inner);
+    }
+
+    /// Close the connection because the queue is full
+    fn overflow(&self, inner: &mut Inner) -> Closed {
+        inner.closed = true;
+        inner.overflowed = true;
+        inner.messages.clear();
+        self.stats.disconnected.fetch_add(1, Ordering::Relaxed);
+        Closed { overflowed: true }
+    }
+
+    fn drop_message(&self, inner: &mut Inner) {
+        inner.dropped += 1;
+        self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn pop(&self) -> Option<Outgoing> {
+        self.inner.lock().unwrap().messages.pop_front()
+    }
+
+    /// Refuse new messages, those already queued are still written
+    pub fn close(&self) {
+        self.inner.lock().unwrap().closed = true;
+    }
+
+    /// Closed and everything queued is taken
+    pub fn is_done(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.closed && inner.messages.is_empty()
+    }
+
+    /// Closed because the consumer didn't keep up, nothing more should be written
+    pub fn overflowed(&self) -> bool {
+        self.inner.lock().unwrap().overflowed
+    }
+
+    /// The notification to replay from, once everything queued is written.
+    /// Returned once until the queue is refilled.
+    pub fn start_replay(&self) -> Option<usize> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.replaying || !inner.messages.is_empty() {
+            return None;
+        }
+        inner.replaying = inner.spilled_from.is_some();
+        inner.spilled_from
+    }
+
+    /// Queue replayed notifications, as many as fit.
+    /// Those left while they were looked up are replayed next.
+    pub fn refill(&self, notifications: impl IntoIterator<Item = (usize, String)>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.replaying = false;
+        inner.spilled_from = inner.missed.take();
+        for (id, msg) in notifications {
+            if inner.messages.len() >= self.capacity {
+                inner.spilled_from = Some(inner.spilled_from.map_or(id, |missed| missed.min(id)));
+                return;
+            }
+            inner.messages.push_back(Outgoing::Notification(id, msg));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().messages.len()
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.inner.lock().unwrap().dropped
+    }
+}
+
+#[test]
+fn slow_consumer_policies() {
+    let stats = Arc::new(QueueStats::default());
+    let message = |n: usize| Outgoing::Notification(n, n.to_string());
+
+    let queue = Queue::new(2, SlowConsumers::DropOldest, stats.clone());
+    for n in 1..=3 {
+        queue.push(message(n)).unwrap();
+    }
+    assert!(matches!(queue.pop(), Some(Outgoing::Notification(2, _))));
+    assert_eq!(queue.dropped(), 1);
+
+    let queue = Queue::new(2, SlowConsumers::Spill, stats.clone());
+    for n in 1..=4 {
+        queue.push(message(n)).unwrap();
+    }
+    assert_eq!(queue.start_replay(), None);
+    queue.pop();
+    queue.pop();
+    assert_eq!(queue.start_replay(), Some(3));
+    assert_eq!(queue.start_replay(), None);
+    // 5 was left while 3 and 4 were looked up
+    queue.push(message(5)).unwrap();
+    queue.refill([(3, String::from("3")), (4, String::from("4"))]);
+    queue.pop();
+    queue.pop();
+    assert_eq!(queue.start_replay(), Some(5));
+
+    let queue = Queue::new(2, SlowConsumers::Disconnect, stats.clone());
+    queue.push(message(1)).unwrap();
+    queue.push(message(2)).unwrap();
+    assert!(queue.push(message(3)).is_err());
+    assert!(queue.pop().is_none());
+    assert!(queue.is_done() && queue.overflowed());
+    assert_eq!(stats.dropped.load(Ordering::Relaxed), 4);
+    assert_eq!(stats.disconnected.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn replies_are_not_dropped() {
+    let stats = Arc::new(QueueStats::default());
+    let reply = |line: &str| Outgoing::Message(line.to_owned());
+
+    // notifications make room for replies, but not the other way around
+    let queue = Queue::new(2, SlowConsumers::DropOldest, stats.clone());
+    queue.push(Outgoing::Notification(1, String::from("1"))).unwrap();
+    queue.push(reply("a")).unwrap();
+    queue.push(reply("b")).unwrap();
+    queue.push(Outgoing::Notification(2, String::from("2"))).unwrap();
+    assert!(matches!(queue.pop(), Some(Outgoing::Message(line)) if line == "a"));
+    queue.push(reply("c")).unwrap();
+    assert!(queue.push(reply("d")).is_err());
+    assert!(queue.overflowed());
+
+    // the notification a reply took the place of is replayed
+    let queue = Queue::new(2, SlowConsumers::Spill, stats.clone());
+    queue.push(Outgoing::Notification(1, String::from("1"))).unwrap();
+    queue.push(Outgoing::Notification(2, String::from("2"))).unwrap();
+    queue.push(reply("a")).unwrap();
+    assert!(matches!(queue.pop(), Some(Outgoing::Notification(1, _))));
+    assert!(matches!(queue.pop(), Some(Outgoing::Message(_))));
+    assert_eq!(queue.start_replay(), Some(2));
+    queue.push(reply("b")).unwrap();
+    queue.push(reply("c")).unwrap();
+    assert!(queue.push(reply("d")).is_err());
+    assert_eq!(stats.dropped.load(Ordering::Relaxed), 3);
+    assert_eq!(stats.disconnected.load(Ordering::Relaxed), 2);
+}
@@ -0,0 +1,52 @@
+//! the connections of a server
+//!
+//! Connections are spread over shards by their id,
+//! so connecting and disconnecting don't wait for a broadcast to finish.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use crate::server::client::ClientHandle;
+
+const SHARDS: usize = 16;
+
+#[derive(Default)]
+pub struct Registry {
+    shards: [RwLock<HashMap<usize, ClientHandle>>; SHARDS],
+}
+
+impl Registry {
+    fn shard(&self, id: usize) -> &RwLock<HashMap<usize, ClientHandle>> {
+        &self.shards[id % SHARDS]
+    }
+
+    pub fn insert(&self, client: ClientHandle) {
+        self.shard(client.id).write().unwrap().insert(client.id, client);
+    }
+
+    pub fn remove(&self, id: usize) -> Option<ClientHandle> {
+        self.shard(id).write().unwrap().remove(&id)
+    }
+
+    /// Run `f` for every connection, one shard at a time
+    pub fn for_each(&self, mut f: impl FnMut(&ClientHandle)) {
+        for shard in &self.shards {
+            shard.read().unwrap().values().for_each(&mut f);
+        }
+    }
+
+    /// The connections for which `f` returns true
+    pub fn filter(&self, mut f: impl FnMut(&ClientHandle) -> bool) -> Vec<ClientHandle> {
+        let mut clients = vec![];
+        self.for_each(|c| {
+            if f(c) {
+                clients.push(c.clone());
+            }
+        });
+        clients
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().unwrap().len()).sum()
+    }
+}
@@ -0,0 +1,100 @@
+//! deciding which logins a notification is delivered to
+//!
+//! A recipient is one of:
+//! - `user` which matches the login `user` and every `user@host` login
+//! - `user@host` which only matches that exact login
+//! - `@group` which matches every user in the unix group `group`
+
+use std::ffi::CString;
+
+use nix::unistd::Group;
+use nix::unistd::User;
+
+use notificationd::notifications::NotificationDetails;
+
+/// The user part of a `user@host` login
+pub fn base_user(login: &str) -> &str {
+    login.split_once('@').map_or(login, |(user, _host)| user)
+}
+
+/// A logged in user with the unix groups it is a member of,
+/// which are looked up once on LOGIN rather than for every notification
+#[derive(Debug, Clone, Default)]
+pub struct Login {
+    pub name: String,
+    pub groups: Vec<String>,
+}
+
+impl Login {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            groups: groups(base_user(name)),
+        }
+    }
+
+    /// Every recipient that addresses this login
+    pub fn recipients(&self) -> Vec<String> {
+        let mut recipients = vec![base_user(&self.name).to_owned()];
+        if self.name.contains('@') {
+            recipients.push(self.name.clone());
+        }
+        recipients.extend(self.groups.iter().map(|group| format!("@{group}")));
+        recipients
+    }
+}
+
+/// The unix groups `user` is a member of, none if it is not a unix user
+fn groups(user: &str) -> Vec<String> {
+    let Ok(Some(u)) = User::from_name(user) else {
+        return vec![];
+    };
+    let Ok(name) = CString::new(user) else {
+        return vec![];
+    };
+    nix::unistd::getgrouplist(&name, u.gid)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|gid| Group::from_gid(gid).ok().flatten())
+        .map(|g| g.name)
+        .collect()
+}
+
+/// Check if a single recipient addresses `login`
+pub fn matches(recipient: &str, login: &Login) -> bool {
+    if let Some(group) = recipient.strip_prefix('@') {
+        login.groups.iter().any(|g| g == group)
+    } else if recipient.contains('@') {
+        recipient == login.name
+    } else {
+        recipient == base_user(&login.name)
+    }
+}
+
+/// The recipients of a notification.
+/// When none were specified it is addressed to the sender.
+pub fn recipients(details: &NotificationDetails) -> Vec<String> {
+    if details.recipients.is_empty() {
+        details.user.iter().map(|u| base_user(u).to_owned()).collect()
+    } else {
+        details.recipients.clone()
+    }
+}
+
+/// Check if a notification should be delivered to `login`
+pub fn is_addressed_to(details: &NotificationDetails, login: &Login) -> bool {
+    recipients(details).iter().any(|r| matches(r, login))
+}
+
+#[test]
+fn login_family() {
+    let login = |name: &str| Login { name: name.to_owned(), groups: vec![String::from("wheel")] };
+    assert!(matches("rein", &login("rein")));
+    assert!(matches("rein", &login("rein@laptop")));
+    assert!(matches("rein@laptop", &login("rein@laptop")));
+    assert!(!matches("rein@laptop", &login("rein@desktop")));
+    assert!(!matches("rein@laptop", &login("rein")));
+    assert!(!matches("rein", &login("reinier@laptop")));
+    assert!(matches("@wheel", &login("rein@laptop")));
+    assert!(!matches("@audio", &login("rein@laptop")));
+}
@@ -0,0 +1,110 @@
+//! integration with the systemd service manager
+//!
+//! Outside of systemd, or on other platforms, these do nothing.
+
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+
+#[cfg(target_os = "linux")]
+use libsystemd as systemd;
+use tracing::warn;
+
+/// Sockets passed to us with socket activation
+#[derive(Default)]
+pub struct Listeners {
+    pub plain: Vec<TcpListener>,
+    /// TCP sockets named `tls`, which start with a TLS handshake
+    pub tls: Vec<TcpListener>,
+    pub unix: Vec<UnixListener>,
+}
+
+impl Listeners {
+    pub fn is_empty(&self) -> bool {
+        self.plain.is_empty() && self.tls.is_empty() && self.unix.is_empty()
+    }
+}
+
+/// Take the sockets in `LISTEN_FDS`
+pub fn listeners() -> anyhow::Result<Listeners> {
+    #[allow(unused_mut)]
+    let mut listeners = Listeners::default();
+    #[cfg(target_os = "linux")]
+    for (fd, name) in systemd::activation::receive_descriptors_with_names(true)? {
+        use std::os::fd::FromRawFd;
+        use std::os::fd::IntoRawFd;
+        use systemd::activation::IsType;
+
+        if fd.is_inet() {
+            // SAFETY: the environment is unset, so the descriptor is only taken once
+            let listener = unsafe { TcpListener::from_raw_fd(fd.into_raw_fd()) };
+            if name == "tls" {
+                listeners.tls.push(listener);
+            } else {
+                listeners.plain.push(listener);
+            }
+        } else if fd.is_unix() {
+            // SAFETY: as above
+            listeners.unix.push(unsafe { UnixListener::from_raw_fd(fd.into_raw_fd()) });
+        } else {
+            anyhow::bail!("unsupported socket {name} passed by systemd");
+        }
+    }
+    Ok(listeners)
+}
+
+/// Report our status, and whether we are done starting up
+pub fn notify(ready: bool, status: &str) {
+    #[cfg(target_os = "linux")]
+    if systemd::daemon::booted() {
+        use systemd::daemon::NotifyState;
+        let mut state = vec![NotifyState::Status(status.to_owned())];
+        if ready {
+            state.push(NotifyState::Ready);
+        }
+        if let Err(e) = systemd::daemon::notify(false, &state) {
+            warn!("failed to notify systemd: {e}");
+        }
+    }
+}
+
+/// Report that we are reloading our configuration, finished by notifying we are ready
+pub fn reloading() {
+    #[cfg(target_os = "linux")]
+    if systemd::daemon::booted()
+        && let Err(e) = systemd::daemon::notify(false, &[systemd::daemon::NotifyState::Reloading])
+    {
+        warn!("failed to notify systemd: {e}");
+    }
+}
+
+/// Report that we are shutting down
+pub fn stopping() {
+    #[cfg(target_os = "linux")]
+    if systemd::daemon::booted()
+        && let Err(e) = systemd::daemon::notify(false, &[systemd::daemon::NotifyState::Stopping])
+    {
+        warn!("failed to notify systemd: {e}");
+    }
+}
+
+/// Send watchdog keepalives while `alive` returns true, if the service has a watchdog.
+/// `alive` may block, in which case the watchdog fires.
+pub fn watchdog(alive: impl Fn() -> bool + Send + 'static) {
+    #[cfg(target_os = "linux")]
+    if let Some(timeout) = systemd::daemon::watchdog_enabled(true) {
+        let interval = timeout / 2;
+        std::thread::Builder::new()
+            .name(String::from("watchdog"))
+            .spawn(move || {
+                while alive() {
+                    if let Err(e) = systemd::daemon::notify(false, &[systemd::daemon::NotifyState::Watchdog]) {
+                        warn!("failed to notify systemd: {e}");
+                    }
+                    std::thread::sleep(interval);
+                }
+            })
+            .expect("failed to spawn watchdog thread");
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = alive;
+}
@@ -0,0 +1,359 @@
+//! TLS for the line protocol, shared by the server and client
+//!
+//! Client connections are read and written from different threads,
+//! so [Stream] shares a single rustls session between its clones.
+//! The session can be added after the fact for STARTTLS.
+
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::net::TcpStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use anyhow::Context;
+use anyhow::anyhow;
+use anyhow::bail;
+use rustls::DigitallySignedStruct;
+use rustls::RootCertStore;
+use rustls::SignatureScheme;
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::client::danger::ServerCertVerified;
+use rustls::client::danger::ServerCertVerifier;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::PrivateKeyDer;
+use rustls::pki_types::ServerName;
+use rustls::pki_types::UnixTime;
+use rustls::pki_types::pem::PemObject;
+use rustls::server::WebPkiClientVerifier;
+use sha2::Digest;
+use sha2::Sha256;
+
+/// TLS options for the server,
+/// from the command line or the `[server.tls]` section of the configuration
+#[derive(clap::Args, serde_derive::Deserialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerTlsArgs {
+    /// PEM certificate chain to offer to clients
+    #[arg(long)]
+    #[serde(rename = "cert")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key of the certificate
+    #[arg(long)]
+    #[serde(rename = "key")]
+    pub tls_key: Option<PathBuf>,
+    /// Address to accept implicit TLS connections on
+    #[arg(long)]
+    #[serde(rename = "bind")]
+    pub tls_bind: Option<String>,
+    /// CA to verify client certificates with
+    #[arg(long)]
+    #[serde(rename = "client_ca")]
+    pub tls_client_ca: Option<PathBuf>,
+}
+
+impl ServerTlsArgs {
+    /// Use these options, falling back to those in `config`
+    pub fn or(self, config: Self) -> Self {
+        Self {
+            tls_cert: self.tls_cert.or(config.tls_cert),
+            tls_key: self.tls_key.or(config.tls_key),
+            tls_bind: self.tls_bind.or(config.tls_bind),
+            tls_client_ca: self.tls_client_ca.or(config.tls_client_ca),
+        }
+    }
+}
+
+/// TLS options for the client,
+/// from the command line or the `[client.tls]` section of the configuration
+#[derive(clap::Args, serde_derive::Deserialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientTlsArgs {
+    /// Connect using TLS
+    #[arg(long, conflicts_with = "starttls")]
+    pub tls: bool,
+    /// Upgrade a plain connection using STARTTLS
+    #[arg(long)]
+    pub starttls: bool,
+    /// PEM CA to verify the server with instead of the webpki roots
+    #[arg(long)]
+    #[serde(rename = "ca")]
+    pub tls_ca: Option<PathBuf>,
+    /// Pin the SHA-256 fingerprint of the server certificate
+    #[arg(long, conflicts_with = "tls_ca")]
+    #[serde(rename = "fingerprint")]
+    pub tls_fingerprint: Option<String>,
+    /// PEM client certificate chain
+    #[arg(long)]
+    #[serde(rename = "client_cert")]
+    pub tls_client_cert: Option<PathBuf>,
+    /// PEM private key of the client certificate
+    #[arg(long)]
+    #[serde(rename = "client_key")]
+    pub tls_client_key: Option<PathBuf>,
+}
+
+impl ClientTlsArgs {
+    pub fn enabled(&self) -> bool {
+        self.tls || self.starttls
+    }
+
+    /// Use these options, falling back to those in `config`
+    pub fn or(self, config: Self) -> Self {
+        Self {
+            tls: self.tls || (config.tls && !self.starttls),
+            starttls: self.starttls || (config.starttls && !self.tls),
+            tls_ca: self.tls_ca.or(config.tls_ca),
+            tls_fingerprint: self.tls_fingerprint.or(config.tls_fingerprint),
+            tls_client_cert: self.tls_client_cert.or(config.tls_client_cert),
+            tls_client_key: self.tls_client_key.or(config.tls_client_key),
+        }
+    }
+}
+
+fn load_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .context(format!("failed to load certificates from {}", path.display()))
+}
+
+fn load_key(path: &Path) -> anyhow::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).context(format!("failed to load key from {}", path.display()))
+}
+
+fn load_roots(path: &Path) -> anyhow::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+pub fn server_config(args: &ServerTlsArgs) -> anyhow::Result<Option<Arc<rustls::ServerConfig>>> {
+    let (cert, key) = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) if args.tls_bind.is_none() && args.tls_client_ca.is_none() => return Ok(None),
+        _ => bail!("TLS needs both a certificate and a key"),
+    };
+    let builder = rustls::ServerConfig::builder();
+    let builder = match &args.tls_client_ca {
+        Some(ca) => builder.with_client_cert_verifier(
+            WebPkiClientVerifier::builder(Arc::new(load_roots(ca)?))
+                .allow_unauthenticated()
+                .build()?,
+        ),
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(load_certs(cert)?, load_key(key)?)?;
+    Ok(Some(Arc::new(config)))
+}
+
+pub fn client_config(args: &ClientTlsArgs) -> anyhow::Result<Arc<rustls::ClientConfig>> {
+    let builder = rustls::ClientConfig::builder();
+    let builder = if let Some(fingerprint) = &args.tls_fingerprint {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(FingerprintVerifier::new(fingerprint)?))
+    } else if let Some(ca) = &args.tls_ca {
+        builder.with_root_certificates(load_roots(ca)?)
+    } else {
+        builder.with_root_certificates(RootCertStore::from_iter(
+            webpki_roots::TLS_SERVER_ROOTS.iter().cloned(),
+        ))
+    };
+    let config = match (&args.tls_client_cert, &args.tls_client_key) {
+        (Some(cert), Some(key)) => builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => bail!("a client certificate needs both a certificate and a key"),
+    };
+    Ok(Arc::new(config))
+}
+
+/// Start a client session for the host part of a `host:port` address
+pub fn client_connection(
+    config: Arc<rustls::ClientConfig>,
+    address: &str,
+) -> anyhow::Result<rustls::ClientConnection> {
+    let host = address
+        .rsplit_once(':')
+        .map_or(address, |(host, _port)| host)
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let name = ServerName::try_from(host.to_owned())?;
+    Ok(rustls::ClientConnection::new(config, name)?)
+}
+
+/// Accepts a server certificate only by its SHA-256 fingerprint
+#[derive(Debug)]
+struct FingerprintVerifier {
+    fingerprint: Vec<u8>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl FingerprintVerifier {
+    fn new(fingerprint: &str) -> anyhow::Result<Self> {
+        let hex: String = fingerprint.chars().filter(|c| *c != ':').collect();
+        if hex.len() != 64 {
+            return Err(anyhow!("fingerprint should be a SHA-256 hash"));
+        }
+        let fingerprint = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .context("fingerprint is not hexadecimal")?;
+        let provider = CryptoProvider::get_default()
+            .cloned()
+            .unwrap_or_else(|| Arc::new(rustls::crypto::ring::default_provider()));
+        Ok(Self { fingerprint, provider })
+    }
+}
+
+impl ServerCertVerifier for FingerprintVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if Sha256::digest(end_entity).as_slice() == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(String::from("certificate fingerprint mismatch")))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// A connection that may be wrapped in TLS
+pub struct Stream {
+    sock: TcpStream,
+    tls: Arc<Mutex<Option<rustls::Connection>>>,
+}
+
+impl Stream {
+    pub fn plain(sock: TcpStream) -> Self {
+        Self {
+            sock,
+            tls: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Wrap this connection in TLS.
+    /// Any clones made before will also start using the session.
+    pub fn upgrade(&self, conn: impl Into<rustls::Connection>) {
+        *self.tls.lock().unwrap() = Some(conn.into());
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.tls.lock().unwrap().is_some()
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            sock: self.sock.try_clone()?,
+            tls: self.tls.clone(),
+        })
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.sock.peer_addr()
+    }
+}
+
+fn tls_error(e: rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = [0u8; 4096];
+        loop {
+            {
+                let mut guard = self.tls.lock().unwrap();
+                let Some(conn) = guard.as_mut() else {
+                    drop(guard);
+                    return (&self.sock).read(buf);
+                };
+                match conn.reader().read(buf) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    res => return res,
+                }
+                while conn.wants_write() {
+                    conn.write_tls(&mut &self.sock)?;
+                }
+            }
+            // wait for the peer without blocking writers
+            let n = (&self.sock).read(&mut incoming)?;
+            let mut guard = self.tls.lock().unwrap();
+            let conn = guard.as_mut().expect("TLS session removed");
+            let mut incoming = &incoming[..n];
+            loop {
+                conn.read_tls(&mut incoming)?;
+                conn.process_new_packets().map_err(tls_error)?;
+                if incoming.is_empty() {
+                    break;
+                }
+            }
+            while conn.wants_write() {
+                conn.write_tls(&mut &self.sock)?;
+            }
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut guard = self.tls.lock().unwrap();
+        match guard.as_mut() {
+            None => (&self.sock).write(buf),
+            Some(conn) => {
+                let n = conn.writer().write(buf)?;
+                while conn.wants_write() {
+                    conn.write_tls(&mut &self.sock)?;
+                }
+                Ok(n)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&self.sock).flush()
+    }
+}
@@ -1,31 +1,85 @@
-use std::{env, io, path::Path};
-use varlink::*;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::SocketAddr;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::sync::Mutex;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
 use notificationd::levitating_notificationd::{self, *};
-use tracing::{error, warn, info, debug, trace};
+use notificationd::notifications;
+use notificationd::notifications::Icon;
+use notificationd::notifications::NotificationDetails;
+use notificationd::protocol;
+use notificationd::protocol::MAX_ICON_SIZE;
+use nix::sys::socket::getsockopt;
+use nix::sys::socket::sockopt::PeerCredentials;
+use nix::unistd::Uid;
+use tracing::{debug, error, info};
+use varlink::ConnectionHandler;
+
+use crate::client::ConnectionStatus;
+use crate::client::Upstream;
 
 use crate::server::ServerHandle;
+use crate::server::DbError;
+use crate::server::Purge;
+use crate::server::CallbackUrl;
+use crate::server::is_callback_url;
 
-struct VarlinkClientHandles {
-    login: String,
-    consume: bool,
-    server: String,
+pub struct VarlinkClientHandles {
+    /// Where Send is forwarded to
+    pub upstream: Upstream,
+    pub connections: Vec<Arc<Mutex<ConnectionStatus>>>,
 }
 
+#[derive(Clone)]
 struct VarlinkHandles {
     server: Option<ServerHandle>,
-    //client: Option<VarlinkClientHandles>,
+    client: Option<Arc<VarlinkClientHandles>>,
+    /// The user of the process on the other end of the connection
+    caller: Uid,
 }
 
 impl VarlinkInterface for VarlinkHandles {
     fn status(&self, call: &mut dyn Call_Status) -> varlink::Result<()> {
         let server = self.server.as_ref().map(|sh| {
+            let (queue_size, slow_consumers, dropped, disconnected) = sh.queue_config();
+            let queues = sh.queues().into_iter().map(|(peer, login, queued, dropped)| QueueStatus {
+                address: peer.to_string(),
+                login,
+                queued: queued as i64,
+                dropped: dropped as i64,
+            }).collect();
             ServerStatus {
                 bind: self.server.as_ref().unwrap().bind.to_string(),
                 connections: sh.clients_len() as i64,
-                persistent: sh.has_db()
+                persistent: sh.has_db(),
+                queue_size: queue_size as i64,
+                slow_consumers: slow_consumers.to_string(),
+                dropped: dropped as i64,
+                disconnected: disconnected as i64,
+                queues,
             }
         });
-        return  call.reply(server, None);
+        let clients = self.client.as_ref().map(|ch| {
+            ch.connections.iter().map(|status| {
+                let status = status.lock().unwrap();
+                ClientStatus {
+                    server: status.server.clone(),
+                    login: status.login.clone(),
+                    consume: status.consume,
+                    connected: status.connected_since.is_some(),
+                    last_id: status.last_id.map(|id| id as i64),
+                    reconnects: status.reconnects as i64,
+                    uptime: status.connected_since.map_or(0, |since| since.elapsed().as_secs() as i64),
+                }
+            }).collect()
+        });
+        call.reply(server, clients)
     }
     fn who(&self, call: &mut dyn Call_Who) -> varlink::Result<()> {
         if let Some(sh) = &self.server {
@@ -34,27 +88,230 @@ impl VarlinkInterface for VarlinkHandles {
                 consume: *consume,
                 address: socket.to_string(),
             }).collect();
-            return call.reply(v);
+            call.reply(v)
         } else {
-            return call.reply(vec![]);
+            call.reply(vec![])
+        }
+    }
+    fn send(
+        &self,
+        call: &mut dyn Call_Send,
+        title: Option<String>,
+        body: Option<String>,
+        icon: Option<String>,
+        icon_png: Option<String>,
+        tags: Option<Vec<String>>,
+        to: Option<Vec<String>>,
+        urgency: Option<Urgency>,
+        actions: Option<Vec<Action>>,
+        callback: Option<String>,
+        quiet: Option<bool>,
+        ephemeral: Option<bool>,
+    ) -> varlink::Result<()> {
+        let tags = tags.unwrap_or_default();
+        if !tags.iter().all(|t| protocol::is_argument(t)) {
+            return call.reply_invalid_parameter(String::from("tags"));
+        }
+        let recipients = to.unwrap_or_default();
+        if !recipients.iter().all(|r| protocol::is_argument(r)) {
+            return call.reply_invalid_parameter(String::from("to"));
+        }
+        let actions = actions.unwrap_or_default();
+        if !actions.iter().all(|a| protocol::is_argument(&a.key)) {
+            return call.reply_invalid_parameter(String::from("actions"));
+        }
+        if callback.as_ref().is_some_and(|url| !is_callback_url(url)) {
+            return call.reply_invalid_parameter(String::from("callback"));
+        }
+        let icon = match icon_png {
+            Some(data) => match BASE64.decode(data) {
+                Ok(data) if data.len() <= MAX_ICON_SIZE => Some(Icon::Png(data)),
+                _ => return call.reply_invalid_parameter(String::from("icon_png")),
+            },
+            None => icon.map(Icon::Name),
+        };
+        let details = NotificationDetails {
+            title,
+            body,
+            icon,
+            tags,
+            recipients,
+            urgency: match urgency {
+                None | Some(Urgency::normal) => notifications::Urgency::Normal,
+                Some(Urgency::low) => notifications::Urgency::Low,
+                Some(Urgency::critical) => notifications::Urgency::Critical,
+            },
+            actions: actions.into_iter().map(|a| (a.key, a.label)).collect(),
+            callback,
+            quiet: quiet.unwrap_or_default(),
+            ephemeral: ephemeral.unwrap_or_default(),
+            ..NotificationDetails::default()
+        };
+        if let Some(sh) = &self.server {
+            let callback = details.callback.as_deref().and_then(CallbackUrl::parse);
+            if callback.is_some_and(|callback| !sh.allows_callback(&callback)) {
+                return call.reply_invalid_parameter(String::from("callback"));
+            }
+            // sent as the caller, like logins over the unix socket of the server
+            let login = match local_login(self.caller) {
+                Ok(login) => login,
+                Err(e) => return call.reply_send_failed(e.to_string()),
+            };
+            let (id, n) = sh.send(details, &login, None);
+            call.reply(id as i64, n as i64)
+        } else if let Some(client) = &self.client {
+            // the server only knows the login of the client daemon
+            if !self.caller.is_root() && self.caller != Uid::current() {
+                return call.reply_send_failed(String::from("only the user running the client may send through it"));
+            }
+            let upstream = &client.upstream;
+            match upstream.send(&details) {
+                Ok((id, n)) => {
+                    info!("Forwarded notification to {}", upstream.connect);
+                    call.reply(id, n)
+                }
+                Err(e) => call.reply_send_failed(format!("{e:#}")),
+            }
+        } else {
+            call.reply_send_failed(String::from("not connected to a server"))
+        }
+    }
+    fn delete(&self, call: &mut dyn Call_Delete, id: i64) -> varlink::Result<()> {
+        let Some(sh) = &self.server else {
+            return call.reply_no_database();
+        };
+        let Ok(key) = u32::try_from(id) else {
+            return call.reply_invalid_parameter(String::from("id"));
+        };
+        match sh.delete_notification(key, self.owner().as_deref()) {
+            Ok(()) => call.reply(),
+            Err(e) => Self::reply_db_error(call, e, id),
+        }
+    }
+    fn purge(
+        &self,
+        call: &mut dyn Call_Purge,
+        user: Option<String>,
+        tag: Option<String>,
+        older_than: Option<i64>,
+    ) -> varlink::Result<()> {
+        let Some(sh) = &self.server else {
+            return call.reply_no_database();
+        };
+        if user.is_none() && tag.is_none() && older_than.is_none() {
+            return call.reply_no_filter();
+        }
+        let Ok(older_than) = older_than.map(u64::try_from).transpose() else {
+            return call.reply_invalid_parameter(String::from("older_than"));
+        };
+        let filter = Purge { user, tag, older_than };
+        match sh.purge_notifications(&filter, self.owner().as_deref()) {
+            Ok(n) => call.reply(n as i64),
+            Err(e) => Self::reply_db_error(call, e, -1),
         }
     }
 }
 
-pub fn init(server: Option<ServerHandle>) -> io::Result<()> {
-    let interface = levitating_notificationd::new(Box::new(VarlinkHandles {server}));
-    let service = varlink::VarlinkService::new("levitating", "notificationd.service", env!("CARGO_PKG_VERSION"), "https//github.com/LevitatingBusinessMan/notificationd", vec![Box::new(interface)]);
-    let config = varlink::ListenConfig::default();
+impl VarlinkHandles {
+    /// The user whose notifications the caller may delete, `None` for root which may delete any
+    fn owner(&self) -> Option<String> {
+        if self.caller.is_root() {
+            return None;
+        }
+        let user = nix::unistd::User::from_uid(self.caller).ok().flatten();
+        Some(user.map_or(self.caller.to_string(), |u| u.name))
+    }
+    fn reply_db_error(call: &mut dyn VarlinkCallError, e: DbError, id: i64) -> varlink::Result<()> {
+        match e {
+            DbError::NoDb => call.reply_no_database(),
+            DbError::NotFound => call.reply_not_found(id),
+            DbError::Forbidden => call.reply_forbidden(),
+            e => call.reply_database_failure(e.to_string()),
+        }
+    }
+}
+
+/// The `user@host` login of `uid` on this host
+fn local_login(uid: Uid) -> nix::Result<String> {
+    let hostname = nix::unistd::gethostname()?;
+    let user = nix::unistd::User::from_uid(uid)?.map_or(uid.to_string(), |u| u.name);
+    Ok(format!("{user}@{}", hostname.to_string_lossy()))
+}
+
+/// Serve the varlink interface on `address`, or the default address of the current user
+pub fn init(
+    server: Option<ServerHandle>,
+    client: Option<VarlinkClientHandles>,
+    address: Option<String>,
+) -> io::Result<()> {
+    let handles = VarlinkHandles { server, client: client.map(Arc::new), caller: Uid::current() };
     let thread = std::thread::Builder::new().name(String::from("varlink"));
-    let address = levitating_notificationd::address(nix::unistd::Uid::current());
+    let address = address.unwrap_or_else(|| levitating_notificationd::address(Uid::current()));
     let address_clone = address.clone();
 
     thread.spawn(move || {
-        let res = varlink::listen(service, &address, &config);
-        error!("listener quit: {res:?}");
+        let listener = match bind(&address) {
+            Ok(listener) => listener,
+            Err(e) => return error!("failed to listen on {address}: {e}"),
+        };
+        for stream in listener.incoming() {
+            let accepted = stream.and_then(|stream| {
+                let creds = getsockopt(&stream, PeerCredentials)?;
+                Ok((stream, Uid::from_raw(creds.uid())))
+            });
+            let (stream, caller) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("failed to accept a varlink connection: {e}");
+                    continue;
+                }
+            };
+            let handles = VarlinkHandles { caller, ..handles.clone() };
+            let thread = std::thread::Builder::new().name(String::from("varlink connection"));
+            if let Err(e) = thread.spawn(move || serve(stream, handles)) {
+                error!("failed to start a varlink connection: {e}");
+            }
+        }
     })?;
 
     info!("Varlink initialized on {}", address_clone);
 
     Ok(())
 }
+
+/// Listen on a `unix:` varlink address, `unix:@name` being an abstract socket
+fn bind(address: &str) -> io::Result<UnixListener> {
+    let path = address
+        .strip_prefix("unix:")
+        .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "not a unix address"))?;
+    let path = path.split(';').next().unwrap_or(path);
+    match path.strip_prefix('@') {
+        Some(name) => UnixListener::bind_addr(&SocketAddr::from_abstract_name(name)?),
+        None => {
+            let _ = std::fs::remove_file(path);
+            UnixListener::bind(path)
+        }
+    }
+}
+
+/// Answer the calls on a connection, with the caller known to the interface
+fn serve(stream: UnixStream, handles: VarlinkHandles) {
+    let interface = levitating_notificationd::new(Box::new(handles));
+    let service = varlink::VarlinkService::new("levitating", "notificationd.service", env!("CARGO_PKG_VERSION"), "https//github.com/LevitatingBusinessMan/notificationd", vec![Box::new(interface)]);
+    let mut reader = match stream.try_clone() {
+        Ok(reader) => BufReader::new(reader),
+        Err(e) => return error!("failed to read varlink connection: {e}"),
+    };
+    let mut writer = stream;
+    let mut upgraded = None;
+    loop {
+        match service.handle(&mut reader, &mut writer, upgraded.clone()) {
+            Ok((_, iface)) => upgraded = iface,
+            Err(e) => return debug!("varlink connection failed: {e}"),
+        }
+        // done once the caller hung up
+        if reader.fill_buf().map_or(true, |buf| buf.is_empty()) {
+            return;
+        }
+    }
+}
@@ -0,0 +1,385 @@
+//! a client for sending and receiving notifications
+//!
+//! ```no_run
+//! use notificationd::client::Client;
+//! use notificationd::notifications::NotificationDetails;
+//!
+//! let mut client = Client::connect("notifications.example.org:6606")?;
+//! client.login("backup", Some("secret"))?;
+//! let mut details = NotificationDetails::new();
+//! details.title = Some(String::from("Backup finished"));
+//! details.recipients = vec![String::from("rein")];
+//! let sent = client.send(&details)?;
+//! println!("sent notification {:?} to {} devices", sent.id, sent.delivered);
+//! # Ok::<(), notificationd::client::Error>(())
+//! ```
+//!
+//! Commands are sent with an id, so their replies can be told apart
+//! from notifications the server relays in between.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+
+use crate::notifications::NotificationDetails;
+use crate::protocol::Command;
+use crate::protocol::InvalidArgument;
+use crate::protocol::Reply;
+use crate::protocol::ServerLine;
+use crate::protocol::ServerMessage;
+
+#[cfg(feature = "tokio")]
+pub mod tokio;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// A detail can't be sent
+    Invalid(InvalidArgument),
+    /// The server answered with a failure reply
+    Failed(Reply),
+    /// The server closed the connection
+    Closed,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{e}"),
+            Error::Invalid(e) => write!(f, "{e}"),
+            Error::Failed(reply) => write!(f, "server replied {reply}"),
+            Error::Closed => write!(f, "connection closed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Invalid(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<InvalidArgument> for Error {
+    fn from(e: InvalidArgument) -> Self {
+        Error::Invalid(e)
+    }
+}
+
+/// Something the server sent on its own
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A notification relayed to this connection after [Client::consume]
+    Notification(NotificationDetails),
+    /// A notification was dismissed on another device of this user
+    Dismissed(usize),
+    /// `login` invoked an action of a notification sent over this connection
+    Invoked { id: usize, key: String, login: String },
+    Notice(String),
+}
+
+/// The result of sending a notification
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sent {
+    /// The id of the stored notification, unknown with older servers
+    pub id: Option<usize>,
+    /// How many connections it was relayed to
+    pub delivered: usize,
+    /// Failure replies to details the server rejected, it was sent without them
+    pub rejected: Vec<Reply>,
+}
+
+impl Sent {
+    /// Read the reply to SEND
+    fn new(args: &[String], rejected: Vec<Reply>) -> Self {
+        Self {
+            id: args.get(1).and_then(|id| id.parse().ok()),
+            delivered: args.first().and_then(|n| n.parse().ok()).unwrap_or(0),
+            rejected,
+        }
+    }
+}
+
+/// A connection to a notificationd server.
+///
+/// The stream can be anything, like a TLS stream or a unix socket.
+pub struct Client<S = TcpStream> {
+    stream: BufReader<S>,
+    next_id: u32,
+    /// Events received while waiting for a reply
+    pending: VecDeque<Event>,
+    /// The notification being received
+    partial: Option<NotificationDetails>,
+}
+
+impl Client<TcpStream> {
+    /// Connect over plain TCP
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self, Error> {
+        Ok(Self::new(TcpStream::connect(addr)?))
+    }
+}
+
+impl<S: Read + Write> Client<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+            next_id: 1,
+            pending: VecDeque::new(),
+            partial: None,
+        }
+    }
+
+    /// Log in, without a password the server has to allow it
+    pub fn login(&mut self, user: &str, password: Option<&str>) -> Result<(), Error> {
+        let id = self.write(&[Command::Login {
+            user: Some(user.to_owned()),
+            password: password.map(str::to_owned),
+        }])?;
+        self.reply(id)?;
+        Ok(())
+    }
+
+    /// Send a notification, replacing any details set before
+    pub fn send(&mut self, details: &NotificationDetails) -> Result<Sent, Error> {
+        // SEND has an id of its own, as it still runs when one of the details is rejected
+        let details_id = self.write(&Command::compose(details)?)?;
+        let id = self.write(&[Command::Send])?;
+        let mut rejected = vec![];
+        loop {
+            let reply = self.next_reply()?;
+            match reply.id {
+                Some(i) if i == details_id => rejected.push(reply),
+                Some(i) if i == id => {
+                    let args = reply.result.clone().map_err(|_| Error::Failed(reply))?;
+                    return Ok(Sent::new(&args, rejected));
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// The last `limit` notifications visible to us, or all of them
+    pub fn history(&mut self, limit: Option<u32>) -> Result<Vec<NotificationDetails>, Error> {
+        let id = self.write(&[Command::History(limit)])?;
+        // HISTORY has no final reply, so wait for the reply to the next command
+        let done = self.write(&[Command::Version])?;
+        let mut notifications = vec![];
+        loop {
+            let reply = self.next_reply()?;
+            match reply.id {
+                Some(i) if i == id && reply.result.is_err() => return Err(Error::Failed(reply)),
+                Some(i) if i == id => reply.add_history(&mut notifications),
+                Some(i) if i == done => return Ok(notifications),
+                _ => (),
+            }
+        }
+    }
+
+    /// The notifications addressed to us after the one with id `offset`
+    pub fn since(&mut self, offset: u32) -> Result<Vec<NotificationDetails>, Error> {
+        let id = self.write(&[Command::Since(offset)])?;
+        let mut notifications = vec![];
+        loop {
+            match self.receive()? {
+                Ok(Event::Notification(details)) => notifications.push(details),
+                Ok(event) => self.pending.push_back(event),
+                Err(reply) if reply.id == Some(id) && reply.result.is_err() => return Err(Error::Failed(reply)),
+                Err(reply) if reply.id == Some(id) => return Ok(notifications),
+                Err(_) => (),
+            }
+        }
+    }
+
+    /// Have notifications addressed to us relayed to this connection,
+    /// read them with [Client::notifications]
+    pub fn consume(&mut self) -> Result<(), Error> {
+        let id = self.write(&[Command::Consume(true)])?;
+        self.reply(id)?;
+        Ok(())
+    }
+
+    /// The stream of notifications and other events, ending when the connection is closed
+    pub fn notifications(&mut self) -> impl Iterator<Item = Result<Event, Error>> + '_ {
+        std::iter::from_fn(move || {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(Ok(event));
+            }
+            loop {
+                match self.receive() {
+                    Ok(Ok(event)) => return Some(Ok(event)),
+                    Ok(Err(_)) => continue,
+                    Err(Error::Closed) => return None,
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+        })
+    }
+
+    /// Close the connection once everything sent before is answered
+    pub fn quit(mut self) -> Result<(), Error> {
+        self.write(&[Command::Quit])?;
+        Ok(())
+    }
+
+    pub fn get_ref(&self) -> &S {
+        self.stream.get_ref()
+    }
+
+    /// Write commands with a new id, returning it
+    fn write(&mut self, commands: &[Command]) -> Result<u32, Error> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let lines: String = commands.iter().map(|command| command.line(Some(id))).collect();
+        let stream = self.stream.get_mut();
+        stream.write_all(lines.as_bytes())?;
+        stream.flush()?;
+        Ok(id)
+    }
+
+    /// Wait for the final reply to command `id`, keeping events for later
+    fn reply(&mut self, id: u32) -> Result<Vec<String>, Error> {
+        loop {
+            let reply = self.next_reply()?;
+            if reply.id == Some(id) {
+                return reply.result.clone().map_err(|_| Error::Failed(reply));
+            }
+        }
+    }
+
+    fn next_reply(&mut self) -> Result<Reply, Error> {
+        loop {
+            match self.receive()? {
+                Ok(event) => self.pending.push_back(event),
+                Err(reply) => return Ok(reply),
+            }
+        }
+    }
+
+    /// Read until an event or a reply is complete
+    fn receive(&mut self) -> Result<Result<Event, Reply>, Error> {
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line)? == 0 {
+                return Err(Error::Closed);
+            }
+            match ServerLine::parse(line.trim_end_matches(['\r', '\n'])) {
+                Some(ServerLine::Reply(reply)) => return Ok(Err(reply)),
+                Some(ServerLine::Message(msg)) => {
+                    if let Some(event) = event(&mut self.partial, msg) {
+                        return Ok(Ok(event));
+                    }
+                }
+                None => (),
+            }
+        }
+    }
+}
+
+/// Turn a message into an event, assembling notifications in `partial`
+fn event(partial: &mut Option<NotificationDetails>, msg: ServerMessage) -> Option<Event> {
+    let event = match msg {
+        ServerMessage::NotifyStart { user, id, timestamp } => {
+            let mut details = NotificationDetails::new();
+            details.id = Some(id);
+            details.user = Some(user);
+            details.timestamp = timestamp;
+            *partial = Some(details);
+            return None;
+        }
+        ServerMessage::NotifyEnd(_) => Event::Notification(partial.take()?),
+        ServerMessage::Dismiss(id) => Event::Dismissed(id),
+        ServerMessage::Invoked { id, key, login } => Event::Invoked { id, key, login },
+        ServerMessage::Notice(text) => Event::Notice(text),
+        detail => {
+            if let Some(details) = partial {
+                detail.add_to(details);
+            }
+            return None;
+        }
+    };
+    Some(event)
+}
+
+/// A stream replaying what a server would send
+#[cfg(test)]
+struct Replay {
+    input: io::Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+#[cfg(test)]
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+#[cfg(test)]
+impl Write for Replay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn replies_and_events() {
+    let server = [
+        "1 +LOGIN : Welcome rein",
+        "$DISMISS 4",
+        &format!("2 -ICON TOO_LARGE : Icons may be at most {} bytes.", crate::protocol::MAX_ICON_SIZE),
+        "3 +SEND 1 7",
+        "4 +HISTORY 7 rein : 2025-01-01 12:00:00",
+        "4 +HISTORY TITLE : Backup finished",
+        "4 +HISTORY BODY : 12 GB",
+        "5 +VERSION notificationd 0.7.10",
+        "$NOTIFY_START bob 8",
+        "$TITLE: Lunch?",
+        "$NOTIFY_END 8",
+        "6 +SINCE 1",
+        "8 -SEND FORBIDDEN : Your credentials do not allow this.",
+    ];
+    let stream = Replay {
+        input: io::Cursor::new(server.map(|line| format!("{line}\r\n")).concat().into_bytes()),
+        output: vec![],
+    };
+    let mut client = Client::new(stream);
+    client.login("rein", Some("hunter2")).unwrap();
+
+    let mut details = NotificationDetails::new();
+    details.title = Some(String::from("Backup finished"));
+    let sent = client.send(&details).unwrap();
+    assert_eq!((sent.id, sent.delivered), (Some(7), 1));
+    assert_eq!(sent.rejected[0].result, Err(crate::protocol::ErrorCode::TooLarge));
+
+    let history = client.history(None).unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].body.as_deref(), Some("12 GB\n"));
+
+    let since = client.since(6).unwrap();
+    assert_eq!(since[0].title.as_deref(), Some("Lunch?"));
+    assert!(matches!(client.send(&details), Err(Error::Failed(_))));
+
+    let events: Vec<_> = client.notifications().map(Result::unwrap).collect();
+    assert_eq!(events, vec![Event::Dismissed(4)]);
+    let sent = String::from_utf8(client.get_ref().output.clone()).unwrap();
+    assert!(sent.starts_with("1 LOGIN rein hunter2\r\n2 RESET\r\n2 TITLE: Backup finished\r\n3 SEND\r\n4 HISTORY\r\n5 VERSION\r\n"));
+}
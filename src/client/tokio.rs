@@ -0,0 +1,336 @@
+//! an async client, running on tokio
+//!
+//! ```no_run
+//! use notificationd::client::tokio::Client;
+//! use notificationd::notifications::NotificationDetails;
+//!
+//! # async fn run() -> Result<(), notificationd::client::Error> {
+//! let (client, mut events) = Client::connect("notifications.example.org:6606").await?;
+//! client.login("backup", Some("secret")).await?;
+//! let mut details = NotificationDetails::new();
+//! details.title = Some(String::from("Backup finished"));
+//! let sent = client.send(&details).await?;
+//! println!("sent notification {:?} to {} devices", sent.id, sent.delivered);
+//!
+//! client.consume().await?;
+//! while let Some(event) = events.recv().await {
+//!     println!("{:?}", event?);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Commands are pipelined: every request is written under its own id right away,
+//! and a task reading the connection hands each reply to the request with that id.
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::task::Context;
+use std::task::Poll;
+
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::net::TcpStream;
+use tokio::net::ToSocketAddrs;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::notifications::NotificationDetails;
+use crate::protocol::Command;
+use crate::protocol::Reply;
+use crate::protocol::ServerLine;
+
+use super::Error;
+use super::Event;
+use super::Sent;
+
+/// A connection to a notificationd server.
+///
+/// Clones share the connection, which is closed once all of them are dropped.
+#[derive(Clone)]
+pub struct Client {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    writer: tokio::sync::Mutex<Box<dyn AsyncWrite + Send + Unpin>>,
+    next_id: AtomicU32,
+    waiting: Arc<std::sync::Mutex<Waiting>>,
+    reader: JoinHandle<()>,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Requests waiting for their replies
+#[derive(Default)]
+struct Waiting {
+    replies: HashMap<u32, mpsc::UnboundedSender<Reply>>,
+    /// The connection was closed, no more replies will come
+    closed: bool,
+}
+
+/// The notifications and other events the server sent on its own,
+/// ending when the connection is closed
+pub struct Events {
+    events: mpsc::UnboundedReceiver<Result<Event, Error>>,
+}
+
+impl Events {
+    pub async fn recv(&mut self) -> Option<Result<Event, Error>> {
+        self.events.recv().await
+    }
+}
+
+impl futures_core::Stream for Events {
+    type Item = Result<Event, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+impl Client {
+    /// Connect over plain TCP
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<(Self, Events), Error> {
+        Ok(Self::new(TcpStream::connect(addr).await?))
+    }
+
+    /// Start reading from `stream` on a new task.
+    /// Events are kept until they are received, so keep reading them after [Client::consume].
+    pub fn new<S>(stream: S) -> (Self, Events)
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (reader, writer) = tokio::io::split(stream);
+        let waiting = Arc::new(std::sync::Mutex::new(Waiting::default()));
+        let (sender, events) = mpsc::unbounded_channel();
+        let reader = tokio::spawn(read(BufReader::new(reader), waiting.clone(), sender));
+        let shared = Shared {
+            writer: tokio::sync::Mutex::new(Box::new(writer)),
+            next_id: AtomicU32::new(1),
+            waiting,
+            reader,
+        };
+        (Self { shared: Arc::new(shared) }, Events { events })
+    }
+
+    /// Log in, without a password the server has to allow it
+    pub async fn login(&self, user: &str, password: Option<&str>) -> Result<(), Error> {
+        let login = Command::Login {
+            user: Some(user.to_owned()),
+            password: password.map(str::to_owned),
+        };
+        self.write(&[&[login]]).await?.result().await?;
+        Ok(())
+    }
+
+    /// Send a notification, replacing any details set before
+    pub async fn send(&self, details: &NotificationDetails) -> Result<Sent, Error> {
+        // SEND has an id of its own, as it still runs when one of the details is rejected
+        let mut replies = self.write(&[&Command::compose(details)?, &[Command::Send]]).await?;
+        let (details_id, id) = (replies.ids[0], replies.ids[1]);
+        let mut rejected = vec![];
+        loop {
+            let reply = replies.next().await?;
+            match reply.id {
+                Some(i) if i == details_id => rejected.push(reply),
+                Some(i) if i == id => {
+                    let args = reply.result.clone().map_err(|_| Error::Failed(reply))?;
+                    return Ok(Sent::new(&args, rejected));
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// The last `limit` notifications visible to us, or all of them
+    pub async fn history(&self, limit: Option<u32>) -> Result<Vec<NotificationDetails>, Error> {
+        // HISTORY has no final reply, so wait for the reply to the next command
+        let mut replies = self.write(&[&[Command::History(limit)], &[Command::Version]]).await?;
+        let (id, done) = (replies.ids[0], replies.ids[1]);
+        let mut notifications = vec![];
+        loop {
+            let reply = replies.next().await?;
+            match reply.id {
+                Some(i) if i == id && reply.result.is_err() => return Err(Error::Failed(reply)),
+                Some(i) if i == id => reply.add_history(&mut notifications),
+                Some(i) if i == done => return Ok(notifications),
+                _ => (),
+            }
+        }
+    }
+
+    /// Have the notifications addressed to us after the one with id `offset`
+    /// relayed to the events, returning how many there were
+    pub async fn since(&self, offset: u32) -> Result<usize, Error> {
+        let args = self.write(&[&[Command::Since(offset)]]).await?.result().await?;
+        Ok(args.first().and_then(|n| n.parse().ok()).unwrap_or(0))
+    }
+
+    /// Have notifications addressed to us relayed to the events
+    pub async fn consume(&self) -> Result<(), Error> {
+        self.write(&[&[Command::Consume(true)]]).await?.result().await?;
+        Ok(())
+    }
+
+    /// Close the connection once everything sent before is answered
+    pub async fn quit(self) -> Result<(), Error> {
+        self.write(&[&[Command::Quit]]).await?;
+        Ok(())
+    }
+
+    /// Write each batch of commands under a new id,
+    /// returning where the replies to them arrive
+    async fn write(&self, batches: &[&[Command]]) -> Result<Replies, Error> {
+        let ids: Vec<u32> = batches.iter().map(|_| self.shared.next_id.fetch_add(1, Ordering::Relaxed)).collect();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        {
+            let mut waiting = self.shared.waiting.lock().unwrap();
+            if waiting.closed {
+                return Err(Error::Closed);
+            }
+            for id in &ids {
+                waiting.replies.insert(*id, sender.clone());
+            }
+        }
+        // registered first, so no reply is missed
+        let replies = Replies {
+            ids,
+            receiver,
+            waiting: self.shared.waiting.clone(),
+        };
+
+        let lines: String = batches
+            .iter()
+            .zip(&replies.ids)
+            .flat_map(|(commands, id)| commands.iter().map(|command| command.line(Some(*id))))
+            .collect();
+        let mut writer = self.shared.writer.lock().await;
+        writer.write_all(lines.as_bytes()).await?;
+        writer.flush().await?;
+        Ok(replies)
+    }
+}
+
+/// The replies to a request, which stops waiting for them when dropped
+struct Replies {
+    ids: Vec<u32>,
+    receiver: mpsc::UnboundedReceiver<Reply>,
+    waiting: Arc<std::sync::Mutex<Waiting>>,
+}
+
+impl Replies {
+    async fn next(&mut self) -> Result<Reply, Error> {
+        self.receiver.recv().await.ok_or(Error::Closed)
+    }
+
+    /// Wait for the first reply
+    async fn result(mut self) -> Result<Vec<String>, Error> {
+        let reply = self.next().await?;
+        reply.result.clone().map_err(|_| Error::Failed(reply))
+    }
+}
+
+impl Drop for Replies {
+    fn drop(&mut self) {
+        let mut waiting = self.waiting.lock().unwrap();
+        for id in &self.ids {
+            waiting.replies.remove(id);
+        }
+    }
+}
+
+/// Hand replies to the requests waiting for them and send everything else to the events
+async fn read(
+    reader: BufReader<impl AsyncRead + Unpin>,
+    waiting: Arc<std::sync::Mutex<Waiting>>,
+    events: mpsc::UnboundedSender<Result<Event, Error>>,
+) {
+    let mut lines = reader.lines();
+    let mut partial = None;
+    let result = loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        };
+        match ServerLine::parse(&line) {
+            Some(ServerLine::Reply(reply)) => {
+                let waiting = waiting.lock().unwrap();
+                if let Some(sender) = reply.id.and_then(|id| waiting.replies.get(&id)) {
+                    let _ = sender.send(reply);
+                }
+            }
+            Some(ServerLine::Message(msg)) => {
+                if let Some(event) = super::event(&mut partial, msg) {
+                    let _ = events.send(Ok(event));
+                }
+            }
+            None => (),
+        }
+    };
+
+    let mut waiting = waiting.lock().unwrap();
+    waiting.closed = true;
+    waiting.replies.clear();
+    if let Err(e) = result {
+        let _ = events.send(Err(e.into()));
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn pipelined_replies() {
+    let (stream, server) = tokio::io::duplex(4096);
+    let (client, mut events) = Client::new(stream);
+    let (server_reader, mut server_writer) = tokio::io::split(server);
+    let mut server_lines = BufReader::new(server_reader).lines();
+
+    let mut details = NotificationDetails::new();
+    details.title = Some(String::from("Backup finished"));
+    let login = client.login("rein", None);
+    let send = client.send(&details);
+    let history = client.history(None);
+    let server = async {
+        let mut received = vec![];
+        while received.len() < 6 {
+            received.push(server_lines.next_line().await.unwrap().unwrap());
+        }
+        assert_eq!(received, ["1 LOGIN rein", "2 RESET", "2 TITLE: Backup finished", "3 SEND", "4 HISTORY", "5 VERSION"]);
+        // answered out of order, with events in between
+        let replies = [
+            "3 +SEND 1 7",
+            "$NOTIFY_START bob 8",
+            "$TITLE: Lunch?",
+            "$NOTIFY_END 8",
+            "4 +HISTORY 7 rein : 2025-01-01 12:00:00",
+            "4 +HISTORY TITLE : Backup finished",
+            "1 +LOGIN : Welcome rein",
+            "5 +VERSION notificationd 0.7.10",
+        ];
+        server_writer.write_all(replies.map(|line| format!("{line}\r\n")).concat().as_bytes()).await.unwrap();
+    };
+    let (login, send, history, ()) = tokio::join!(login, send, history, server);
+    login.unwrap();
+    assert_eq!(send.unwrap(), Sent { id: Some(7), delivered: 1, rejected: vec![] });
+    assert_eq!(history.unwrap()[0].title.as_deref(), Some("Backup finished"));
+
+    let Some(Ok(Event::Notification(details))) = events.recv().await else {
+        panic!("expected a notification");
+    };
+    assert_eq!(details.title.as_deref(), Some("Lunch?"));
+
+    drop((server_lines, server_writer));
+    assert!(events.recv().await.is_none());
+    assert!(matches!(client.consume().await, Err(Error::Closed)));
+}
@@ -0,0 +1,28 @@
+//! parsing of ages like `30d`
+
+/// Parse a duration in seconds, optionally suffixed with `s`, `m`, `h`, `d` or `w`
+pub fn parse(input: &str) -> Option<u64> {
+    let input = input.trim();
+    let (number, unit) = match input.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => input.split_at(i),
+        None => (input, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+#[test]
+fn units() {
+    assert_eq!(parse("90"), Some(90));
+    assert_eq!(parse("2h"), Some(7200));
+    assert_eq!(parse("30d"), Some(30 * 86400));
+    assert_eq!(parse("d"), None);
+    assert_eq!(parse("3y"), None);
+}
@@ -1,13 +1,17 @@
+#![allow(non_camel_case_types, clippy::match_single_binding, clippy::too_many_arguments)]
+
 use std::env;
 
 // you might want to enable rust-analyzer.cargo.loadOutDirsFromCheck
 include!(concat!(env!("OUT_DIR"), "/levitating.notificationd.rs"));
 
-pub const SOCKET_NAME: &'static str = "levitating.notificationd";
+pub const SOCKET_NAME: &str = "levitating.notificationd";
+/// Where the system server listens, as passed with `--varlink` in its systemd unit
+pub const SYSTEM_ADDRESS: &str = "unix:@levitating.notificationd";
 
 pub fn address(uid: nix::unistd::Uid) -> String {
     if env::consts::OS == "linux" && uid.is_root() {
-        return "unix:@levitating.notificationd".to_string()
+        return SYSTEM_ADDRESS.to_string()
     }
 
     let dir = std::env::var("XDG_RUNTIME_DIR").unwrap_or_else(|_| {
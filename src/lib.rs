@@ -1,2 +1,5 @@
+pub mod client;
+pub mod duration;
 pub mod notifications;
+pub mod protocol;
 pub mod levitating_notificationd;
@@ -1,14 +1,91 @@
 
-#[derive(Debug, Clone)]
+/// Icon of a notification
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Icon {
+    /// A freedesktop icon name like `dialog-information`
+    Name(String),
+    /// An inline PNG image
+    Png(Vec<u8>),
+}
+
+/// Urgency of a notification, like the freedesktop `urgency` hint
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Urgency {
+    Low,
+    #[default]
+    Normal,
+    Critical,
+}
+
+impl Urgency {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Urgency::Low => "low",
+            Urgency::Normal => "normal",
+            Urgency::Critical => "critical",
+        }
+    }
+
+    /// The value of the freedesktop `urgency` hint
+    pub fn level(&self) -> u8 {
+        match self {
+            Urgency::Low => 0,
+            Urgency::Normal => 1,
+            Urgency::Critical => 2,
+        }
+    }
+
+    pub fn from_level(level: u8) -> Option<Self> {
+        match level {
+            0 => Some(Urgency::Low),
+            1 => Some(Urgency::Normal),
+            2 => Some(Urgency::Critical),
+            _ => None,
+        }
+    }
+}
+
+impl std::str::FromStr for Urgency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "low" => Ok(Urgency::Low),
+            "normal" => Ok(Urgency::Normal),
+            "critical" => Ok(Urgency::Critical),
+            _ => Err(format!("invalid urgency {s}, expected low, normal or critical")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct NotificationDetails {
     pub id: Option<usize>,
     pub title: Option<String>,
     pub body: Option<String>,
+    pub icon: Option<Icon>,
     pub tags: Vec<String>,
+    /// Users, logins or groups this notification is addressed to
+    pub recipients: Vec<String>,
+    pub urgency: Urgency,
+    /// Actions the user can pick, as keys and labels
+    pub actions: Vec<(String, String)>,
+    /// URL the server posts invoked actions to
+    pub callback: Option<String>,
+    /// Store the notification without displaying it
+    pub quiet: bool,
+    /// Display the notification without storing it
+    pub ephemeral: bool,
     pub user: Option<String>,
     pub timestamp: Option<String>,
 }
 
+impl Default for NotificationDetails {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl NotificationDetails {
     pub fn new() -> Self {
         Self {
@@ -16,7 +93,14 @@ impl NotificationDetails {
             user: None,
             title: None,
             body: None,
+            icon: None,
             tags: vec![],
+            recipients: vec![],
+            urgency: Urgency::Normal,
+            actions: vec![],
+            callback: None,
+            quiet: false,
+            ephemeral: false,
             timestamp: None,
         }
     }
@@ -0,0 +1,813 @@
+//! typed messages of the protocol
+//!
+//! Lines are parsed with [parser::line] first, then turned into a [Command]
+//! on the server or a [ServerLine] on the client. Displaying a message gives
+//! the line it was parsed from, without the line ending.
+
+use std::fmt;
+
+use crate::notifications::Icon;
+use crate::notifications::NotificationDetails;
+use crate::notifications::Urgency;
+
+use super::parser;
+use super::decode_icon_chunk;
+use super::encode_icon_chunk;
+use super::icon_parts;
+use super::is_argument;
+use super::parse_bool;
+
+/// A command sent by a client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Over a unix socket the user may be left out
+    Login { user: Option<String>, password: Option<String> },
+    StartTls,
+    Title(String),
+    /// Append a line to the body, replacing it with `reset`
+    Body { line: Option<String>, reset: bool },
+    /// Set an icon name or append a chunk of a PNG, replacing it with `reset`
+    Icon { icon: Option<Icon>, reset: bool },
+    Quiet(bool),
+    Ephemeral(bool),
+    To(Vec<String>),
+    Tags(Vec<String>),
+    Urgency(Urgency),
+    Action { key: String, label: String },
+    /// None removes the callback
+    Callback(Option<String>),
+    Invoke { id: usize, key: String },
+    Dismiss(usize),
+    Send,
+    Reset,
+    Version,
+    Consume(bool),
+    Quit,
+    /// The last this many notifications, or all of them
+    History(Option<u32>),
+    Since(u32),
+    Delete(Delete),
+    Who,
+}
+
+/// What a DELETE command deletes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Delete {
+    Id(u32),
+    /// All notifications matching every set filter
+    Matching {
+        user: Option<String>,
+        tag: Option<String>,
+        /// In seconds
+        older_than: Option<u64>,
+    },
+}
+
+/// A message the server sends on its own, with the `$` sign
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerMessage {
+    NotifyStart { user: String, id: usize, timestamp: Option<String> },
+    Title(String),
+    Tags(Vec<String>),
+    Urgency(Urgency),
+    Action { key: String, label: String },
+    /// An icon name or a chunk of a PNG
+    Icon(Icon),
+    /// A line of the body
+    Body(String),
+    NotifyEnd(usize),
+    /// `login` invoked an action of a notification we sent
+    Invoked { id: usize, key: String, login: String },
+    /// The notification was dismissed on another device
+    Dismiss(usize),
+    Notice(String),
+}
+
+/// A success or failure reply to a command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    /// The id of the command replied to
+    pub id: Option<u32>,
+    pub command: String,
+    /// The arguments of a success reply, or the error of a failure reply
+    pub result: Result<Vec<String>, ErrorCode>,
+    pub trailing: Option<String>,
+}
+
+/// Error codes of failure replies
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorCode {
+    Parse,
+    MissingTrailing,
+    MissingArg,
+    InvalidArg,
+    InvalidMessage,
+    UnknownCmd,
+    NoLogin,
+    AlreadyLoggedIn,
+    AuthFailed,
+    Forbidden,
+    NoTls,
+    AlreadyTls,
+    TooLarge,
+    NotFound,
+    NoDb,
+    DbFail,
+    /// A code this implementation doesn't know
+    Other(String),
+}
+
+/// Anything a client may receive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerLine {
+    Message(ServerMessage),
+    Reply(Reply),
+}
+
+/// A detail that can't be sent as a single argument, naming the field it was in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidArgument {
+    pub field: &'static str,
+    pub value: String,
+}
+
+impl Command {
+    /// Parse a command, or the failure reply to send when it is invalid
+    pub fn parse(msg: &parser::Message) -> Result<Self, Reply> {
+        let name = msg.command.to_uppercase();
+        let error = |code| Reply::err(msg.id, &name, code);
+        let args = &msg.arguments;
+        let has_arg = |arg: &str| args.iter().any(|a| a.eq_ignore_ascii_case(arg));
+        let trailing = msg.trailing.clone();
+
+        let command = match name.as_ref() {
+            "LOGIN" => Command::Login {
+                user: args.first().cloned(),
+                password: args.get(1).cloned(),
+            },
+            "STARTTLS" => Command::StartTls,
+            "TITLE" => Command::Title(trailing.ok_or_else(|| error(ErrorCode::MissingTrailing))?),
+            "BODY" => {
+                let reset = args.first().is_some_and(|a| a.eq_ignore_ascii_case("RST"));
+                if trailing.is_none() && !reset {
+                    return Err(error(ErrorCode::MissingTrailing));
+                }
+                Command::Body { line: trailing, reset }
+            }
+            "ICON" => {
+                let reset = has_arg("RST");
+                let icon = match trailing {
+                    Some(text) if has_arg("PNG") => match decode_icon_chunk(&text) {
+                        Ok(chunk) => Some(Icon::Png(chunk)),
+                        Err(e) => return Err(error(ErrorCode::InvalidArg).with_trailing(e.to_string())),
+                    },
+                    Some(name) => Some(Icon::Name(name)),
+                    None if reset => None,
+                    None => return Err(error(ErrorCode::MissingTrailing)),
+                };
+                Command::Icon { icon, reset }
+            }
+            "QUIET" => Command::Quiet(parse_bool(args.first()).ok_or_else(|| error(ErrorCode::InvalidArg))?),
+            "EPHERMAL" | "EPHEMERAL" => {
+                Command::Ephemeral(parse_bool(args.first()).ok_or_else(|| error(ErrorCode::InvalidArg))?)
+            }
+            "TO" if args.is_empty() => return Err(error(ErrorCode::MissingArg)),
+            "TO" => Command::To(args.clone()),
+            "TAGS" => Command::Tags(args.clone()),
+            "URGENCY" => match args.first().map(|a| a.parse()) {
+                Some(Ok(urgency)) => Command::Urgency(urgency),
+                Some(Err(e)) => return Err(error(ErrorCode::InvalidArg).with_trailing(e)),
+                None => return Err(error(ErrorCode::MissingArg)),
+            },
+            "ACTION" => match (args.first(), trailing) {
+                (Some(key), Some(label)) => Command::Action { key: key.clone(), label },
+                (None, _) => return Err(error(ErrorCode::MissingArg)),
+                (_, None) => return Err(error(ErrorCode::MissingTrailing)),
+            },
+            "CALLBACK" => match trailing {
+                Some(url) => Command::Callback(Some(url.trim().to_owned()).filter(|url| !url.is_empty())),
+                None => return Err(error(ErrorCode::MissingTrailing)),
+            },
+            "INVOKE" => match (args.first().map(|id| id.parse()), args.get(1)) {
+                (Some(Ok(id)), Some(key)) => Command::Invoke { id, key: key.clone() },
+                (Some(Err(_)), _) => return Err(error(ErrorCode::InvalidArg)),
+                _ => return Err(error(ErrorCode::MissingArg)),
+            },
+            "DISMISS" => match args.first().map(|id| id.parse()) {
+                Some(Ok(id)) => Command::Dismiss(id),
+                Some(Err(_)) => return Err(error(ErrorCode::InvalidArg)),
+                None => return Err(error(ErrorCode::MissingArg)),
+            },
+            "SEND" => Command::Send,
+            "RESET" => Command::Reset,
+            "VERSION" => Command::Version,
+            "CONSUME" => Command::Consume(parse_bool(args.first()).ok_or_else(|| error(ErrorCode::InvalidArg))?),
+            "QUIT" => Command::Quit,
+            "HISTORY" => match args.first().map(|limit| limit.parse()).transpose() {
+                Ok(limit) => Command::History(limit),
+                Err(_) => return Err(error(ErrorCode::InvalidArg)),
+            },
+            "SINCE" => match args.first().map(|offset| offset.parse()) {
+                Some(Ok(offset)) => Command::Since(offset),
+                Some(Err(_)) => return Err(error(ErrorCode::InvalidArg)),
+                None => return Err(error(ErrorCode::MissingArg)),
+            },
+            "DELETE" => Command::Delete(Delete::parse(args).map_err(error)?),
+            "WHO" => Command::Who,
+            _ => {
+                return Err(Reply::err(msg.id, "ERR", ErrorCode::UnknownCmd).with_trailing(format!("I do not know {name}")));
+            }
+        };
+        Ok(command)
+    }
+
+    /// The name of the command, as used in replies
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Login { .. } => "LOGIN",
+            Command::StartTls => "STARTTLS",
+            Command::Title(_) => "TITLE",
+            Command::Body { .. } => "BODY",
+            Command::Icon { .. } => "ICON",
+            Command::Quiet(_) => "QUIET",
+            Command::Ephemeral(_) => "EPHERMAL",
+            Command::To(_) => "TO",
+            Command::Tags(_) => "TAGS",
+            Command::Urgency(_) => "URGENCY",
+            Command::Action { .. } => "ACTION",
+            Command::Callback(_) => "CALLBACK",
+            Command::Invoke { .. } => "INVOKE",
+            Command::Dismiss(_) => "DISMISS",
+            Command::Send => "SEND",
+            Command::Reset => "RESET",
+            Command::Version => "VERSION",
+            Command::Consume(_) => "CONSUME",
+            Command::Quit => "QUIT",
+            Command::History(_) => "HISTORY",
+            Command::Since(_) => "SINCE",
+            Command::Delete(_) => "DELETE",
+            Command::Who => "WHO",
+        }
+    }
+
+    /// The line to send, with an id the reply will carry
+    pub fn line(&self, id: Option<u32>) -> String {
+        match id {
+            Some(id) => format!("{id} {self}\r\n"),
+            None => format!("{self}\r\n"),
+        }
+    }
+
+    /// The commands composing a notification on a fresh state, without SEND.
+    /// Fails if a tag, recipient or action key isn't a single argument.
+    pub fn compose(details: &NotificationDetails) -> Result<Vec<Command>, InvalidArgument> {
+        let invalid = details.tags.iter().map(|tag| ("tag", tag))
+            .chain(details.recipients.iter().map(|recipient| ("recipient", recipient)))
+            .chain(details.actions.iter().map(|(key, _)| ("action", key)))
+            .find(|(_, value)| !is_argument(value));
+        if let Some((field, value)) = invalid {
+            return Err(InvalidArgument { field, value: value.clone() });
+        }
+        let mut commands = vec![Command::Reset];
+        if let Some(title) = &details.title {
+            commands.push(Command::Title(title.clone()));
+        }
+        if !details.tags.is_empty() {
+            commands.push(Command::Tags(details.tags.clone()));
+        }
+        if !details.recipients.is_empty() {
+            commands.push(Command::To(details.recipients.clone()));
+        }
+        if details.urgency != Urgency::Normal {
+            commands.push(Command::Urgency(details.urgency));
+        }
+        for (key, label) in &details.actions {
+            commands.push(Command::Action { key: key.clone(), label: label.clone() });
+        }
+        if let Some(callback) = &details.callback {
+            commands.push(Command::Callback(Some(callback.replace(['\r', '\n'], ""))));
+        }
+        if let Some(icon) = &details.icon {
+            commands.extend(icon_parts(icon).into_iter().map(|icon| Command::Icon { icon: Some(icon), reset: false }));
+        }
+        if let Some(body) = &details.body {
+            commands.extend(body.lines().map(|line| Command::Body { line: Some(line.to_owned()), reset: false }));
+        }
+        if details.quiet {
+            commands.push(Command::Quiet(true));
+        }
+        if details.ephemeral {
+            commands.push(Command::Ephemeral(true));
+        }
+        Ok(commands)
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let on_off = |value: bool| if value { "on" } else { "off" };
+        match self {
+            Command::Login { user, password } => {
+                write!(f, "LOGIN")?;
+                for arg in user.iter().chain(password) {
+                    write!(f, " {arg}")?;
+                }
+                Ok(())
+            }
+            Command::StartTls => write!(f, "STARTTLS"),
+            Command::Title(title) => write!(f, "TITLE: {}", single_line(title)),
+            Command::Body { line, reset } => {
+                write!(f, "BODY{}", if *reset { " RST" } else { "" })?;
+                match line {
+                    Some(line) => write!(f, ": {}", single_line(line)),
+                    None => Ok(()),
+                }
+            }
+            Command::Icon { icon, reset } => {
+                let reset = if *reset { " RST" } else { "" };
+                match icon {
+                    Some(Icon::Name(name)) => write!(f, "ICON{reset}: {}", single_line(name)),
+                    Some(Icon::Png(chunk)) => write!(f, "ICON PNG{reset}: {}", encode_icon_chunk(chunk)),
+                    None => write!(f, "ICON{reset}"),
+                }
+            }
+            Command::Quiet(quiet) => write!(f, "QUIET {}", on_off(*quiet)),
+            Command::Ephemeral(ephemeral) => write!(f, "EPHERMAL {}", on_off(*ephemeral)),
+            Command::To(recipients) => write!(f, "TO {}", recipients.join(" ")),
+            Command::Tags(tags) if tags.is_empty() => write!(f, "TAGS"),
+            Command::Tags(tags) => write!(f, "TAGS {}", tags.join(" ")),
+            Command::Urgency(urgency) => write!(f, "URGENCY {}", urgency.as_str()),
+            Command::Action { key, label } => write!(f, "ACTION {key}: {}", single_line(label)),
+            Command::Callback(url) => write!(f, "CALLBACK: {}", url.as_deref().map(single_line).unwrap_or_default()),
+            Command::Invoke { id, key } => write!(f, "INVOKE {id} {key}"),
+            Command::Dismiss(id) => write!(f, "DISMISS {id}"),
+            Command::Send => write!(f, "SEND"),
+            Command::Reset => write!(f, "RESET"),
+            Command::Version => write!(f, "VERSION"),
+            Command::Consume(consume) => write!(f, "CONSUME {}", on_off(*consume)),
+            Command::Quit => write!(f, "QUIT"),
+            Command::History(Some(limit)) => write!(f, "HISTORY {limit}"),
+            Command::History(None) => write!(f, "HISTORY"),
+            Command::Since(offset) => write!(f, "SINCE {offset}"),
+            Command::Delete(delete) => write!(f, "DELETE {delete}"),
+            Command::Who => write!(f, "WHO"),
+        }
+    }
+}
+
+impl Delete {
+    /// Parse the arguments of DELETE, an id or a filter like `USER rein TAG ci OLDER 7d`
+    fn parse(args: &[String]) -> Result<Self, ErrorCode> {
+        let Some(first) = args.first() else {
+            return Err(ErrorCode::MissingArg);
+        };
+        if let Ok(id) = first.parse() {
+            return Ok(Delete::Id(id));
+        }
+        let (mut user, mut tag, mut older_than) = (None, None, None);
+        for pair in args.chunks(2) {
+            let [key, value] = pair else {
+                return Err(ErrorCode::InvalidArg);
+            };
+            match key.to_uppercase().as_ref() {
+                "USER" => user = Some(value.clone()),
+                "TAG" => tag = Some(value.clone()),
+                "OLDER" => older_than = Some(crate::duration::parse(value).ok_or(ErrorCode::InvalidArg)?),
+                _ => return Err(ErrorCode::InvalidArg),
+            }
+        }
+        Ok(Delete::Matching { user, tag, older_than })
+    }
+}
+
+impl fmt::Display for Delete {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Delete::Id(id) => write!(f, "{id}"),
+            Delete::Matching { user, tag, older_than } => {
+                let mut filters = vec![];
+                if let Some(user) = user {
+                    filters.push(format!("USER {user}"));
+                }
+                if let Some(tag) = tag {
+                    filters.push(format!("TAG {tag}"));
+                }
+                if let Some(older_than) = older_than {
+                    filters.push(format!("OLDER {older_than}"));
+                }
+                write!(f, "{}", filters.join(" "))
+            }
+        }
+    }
+}
+
+impl ServerMessage {
+    /// Parse a message with the `$` sign, None if it is unknown or invalid
+    pub fn parse(msg: &parser::Message) -> Option<Self> {
+        let args = &msg.arguments;
+        let trailing = msg.trailing.clone();
+        let message = match msg.command.to_uppercase().as_ref() {
+            "NOTIFY_START" => ServerMessage::NotifyStart {
+                user: args.first()?.clone(),
+                id: args.get(1)?.parse().ok()?,
+                timestamp: trailing,
+            },
+            "TITLE" => ServerMessage::Title(trailing?),
+            "TAGS" => ServerMessage::Tags(trailing.unwrap_or_default().split_whitespace().map(String::from).collect()),
+            "URGENCY" => ServerMessage::Urgency(args.first()?.parse().ok()?),
+            "ACTION" if args.len() == 1 => ServerMessage::Action { key: args[0].clone(), label: trailing? },
+            "ACTION" => ServerMessage::Invoked {
+                id: args.first()?.parse().ok()?,
+                key: args.get(1)?.clone(),
+                login: trailing.unwrap_or_default(),
+            },
+            "ICON" if args.iter().any(|a| a.eq_ignore_ascii_case("PNG")) => {
+                ServerMessage::Icon(Icon::Png(decode_icon_chunk(&trailing?).ok()?))
+            }
+            "ICON" => ServerMessage::Icon(Icon::Name(trailing?)),
+            "BODY" => ServerMessage::Body(trailing?),
+            "NOTIFY_END" => ServerMessage::NotifyEnd(args.first()?.parse().ok()?),
+            "DISMISS" => ServerMessage::Dismiss(args.first()?.parse().ok()?),
+            "NOTICE" => ServerMessage::Notice(trailing.unwrap_or_default()),
+            _ => return None,
+        };
+        Some(message)
+    }
+
+    pub fn line(&self) -> String {
+        format!("{self}\r\n")
+    }
+
+    /// Add a detail sent between `$NOTIFY_START` and `$NOTIFY_END`
+    pub fn add_to(self, details: &mut NotificationDetails) {
+        match self {
+            ServerMessage::Title(title) => details.title = Some(title),
+            ServerMessage::Tags(tags) => details.tags = tags,
+            ServerMessage::Urgency(urgency) => details.urgency = urgency,
+            ServerMessage::Action { key, label } => details.actions.push((key, label)),
+            ServerMessage::Icon(Icon::Png(chunk)) => match &mut details.icon {
+                Some(Icon::Png(data)) => data.extend(chunk),
+                icon => *icon = Some(Icon::Png(chunk)),
+            },
+            ServerMessage::Icon(icon) => details.icon = Some(icon),
+            ServerMessage::Body(line) => {
+                let body = details.body.get_or_insert_default();
+                body.push_str(&line);
+                body.push('\n');
+            }
+            _ => {}
+        }
+    }
+
+    /// The `$NOTIFY_START` to `$NOTIFY_END` block relaying a stored notification,
+    /// or `None` if it has no id yet
+    pub fn notification(details: &NotificationDetails) -> Option<Vec<ServerMessage>> {
+        let id = details.id?;
+        let mut messages = vec![ServerMessage::NotifyStart {
+            user: details.user.clone().unwrap_or_default(),
+            id,
+            timestamp: details.timestamp.clone(),
+        }];
+        if let Some(title) = &details.title {
+            messages.push(ServerMessage::Title(title.clone()));
+        }
+        if !details.tags.is_empty() {
+            messages.push(ServerMessage::Tags(details.tags.clone()));
+        }
+        if details.urgency != Urgency::Normal {
+            messages.push(ServerMessage::Urgency(details.urgency));
+        }
+        for (key, label) in &details.actions {
+            messages.push(ServerMessage::Action { key: key.clone(), label: label.clone() });
+        }
+        if let Some(icon) = &details.icon {
+            messages.extend(icon_parts(icon).into_iter().map(ServerMessage::Icon));
+        }
+        if let Some(body) = &details.body {
+            messages.extend(body.lines().map(|line| ServerMessage::Body(line.to_owned())));
+        }
+        messages.push(ServerMessage::NotifyEnd(id));
+        Some(messages)
+    }
+}
+
+impl fmt::Display for ServerMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerMessage::NotifyStart { user, id, timestamp } => {
+                write!(f, "$NOTIFY_START {user} {id}")?;
+                match timestamp {
+                    Some(timestamp) => write!(f, " : {}", single_line(timestamp)),
+                    None => Ok(()),
+                }
+            }
+            ServerMessage::Title(title) => write!(f, "$TITLE: {}", single_line(title)),
+            ServerMessage::Tags(tags) => write!(f, "$TAGS: {}", tags.join(" ")),
+            ServerMessage::Urgency(urgency) => write!(f, "$URGENCY {}", urgency.as_str()),
+            ServerMessage::Action { key, label } => write!(f, "$ACTION {key}: {}", single_line(label)),
+            ServerMessage::Icon(Icon::Name(name)) => write!(f, "$ICON: {}", single_line(name)),
+            ServerMessage::Icon(Icon::Png(chunk)) => write!(f, "$ICON PNG: {}", encode_icon_chunk(chunk)),
+            ServerMessage::Body(line) => write!(f, "$BODY: {}", single_line(line)),
+            ServerMessage::NotifyEnd(id) => write!(f, "$NOTIFY_END {id}"),
+            ServerMessage::Invoked { id, key, login } => write!(f, "$ACTION {id} {key} : {login}"),
+            ServerMessage::Dismiss(id) => write!(f, "$DISMISS {id}"),
+            ServerMessage::Notice(text) => write!(f, "$NOTICE : {}", single_line(text)),
+        }
+    }
+}
+
+impl Reply {
+    pub fn ok(id: Option<u32>, command: &str, arguments: Vec<String>) -> Self {
+        Self {
+            id,
+            command: command.to_owned(),
+            result: Ok(arguments),
+            trailing: None,
+        }
+    }
+
+    pub fn err(id: Option<u32>, command: &str, code: ErrorCode) -> Self {
+        Self {
+            id,
+            command: command.to_owned(),
+            result: Err(code),
+            trailing: None,
+        }
+    }
+
+    pub fn with_trailing(mut self, trailing: impl Into<String>) -> Self {
+        self.trailing = Some(trailing.into());
+        self
+    }
+
+    /// Parse a message with the `+` or `-` sign
+    pub fn parse(msg: &parser::Message) -> Option<Self> {
+        let result = match msg.sign? {
+            '+' => Ok(msg.arguments.clone()),
+            '-' => Err(msg.arguments.first().map_or(ErrorCode::Other(String::new()), |code| ErrorCode::from(code.as_str()))),
+            _ => return None,
+        };
+        Some(Self {
+            id: msg.id,
+            command: msg.command.to_uppercase(),
+            result,
+            trailing: msg.trailing.clone(),
+        })
+    }
+
+    pub fn line(&self) -> String {
+        format!("{self}\r\n")
+    }
+
+    /// The HISTORY replies describing a stored notification,
+    /// or `None` if it has no id or sender
+    pub fn history(id: Option<u32>, details: &NotificationDetails) -> Option<Vec<Reply>> {
+        let reply = |args: &[&str]| Reply::ok(id, "HISTORY", args.iter().map(|a| a.to_string()).collect());
+        let mut first = reply(&[&details.id?.to_string(), details.user.as_deref()?]);
+        first.trailing = details.timestamp.clone();
+        let mut replies = vec![first];
+        if let Some(title) = &details.title {
+            replies.push(reply(&["TITLE"]).with_trailing(title));
+        }
+        if !details.tags.is_empty() {
+            replies.push(reply(&["TAGS"]).with_trailing(details.tags.join(" ")));
+        }
+        if details.urgency != Urgency::Normal {
+            replies.push(reply(&["URGENCY", details.urgency.as_str()]));
+        }
+        for (key, label) in &details.actions {
+            replies.push(reply(&["ACTION", key]).with_trailing(label));
+        }
+        if let Some(icon) = &details.icon {
+            for part in icon_parts(icon) {
+                match part {
+                    Icon::Name(name) => replies.push(reply(&["ICON"]).with_trailing(name)),
+                    Icon::Png(chunk) => replies.push(reply(&["ICON", "PNG"]).with_trailing(encode_icon_chunk(&chunk))),
+                }
+            }
+        }
+        if let Some(body) = &details.body {
+            replies.extend(body.lines().map(|line| reply(&["BODY"]).with_trailing(line)));
+        }
+        Some(replies)
+    }
+
+    /// Add a HISTORY reply to the notifications it describes
+    pub fn add_history(&self, notifications: &mut Vec<NotificationDetails>) {
+        let Ok(args) = &self.result else {
+            return;
+        };
+        let trailing = self.trailing.clone();
+        if let Some(id) = args.first().and_then(|id| id.parse().ok()) {
+            let mut details = NotificationDetails::new();
+            details.id = Some(id);
+            details.user = args.get(1).cloned();
+            details.timestamp = trailing;
+            notifications.push(details);
+            return;
+        }
+        let Some(details) = notifications.last_mut() else {
+            return;
+        };
+        let detail = match (args.first().map(|a| a.to_uppercase()).as_deref(), trailing) {
+            (Some("TITLE"), Some(title)) => ServerMessage::Title(title),
+            (Some("TAGS"), tags) => ServerMessage::Tags(tags.unwrap_or_default().split_whitespace().map(String::from).collect()),
+            (Some("URGENCY"), _) => match args.get(1).and_then(|u| u.parse().ok()) {
+                Some(urgency) => ServerMessage::Urgency(urgency),
+                None => return,
+            },
+            (Some("ACTION"), Some(label)) if args.len() > 1 => ServerMessage::Action { key: args[1].clone(), label },
+            (Some("ICON"), Some(text)) if args.iter().any(|a| a.eq_ignore_ascii_case("PNG")) => {
+                match decode_icon_chunk(&text) {
+                    Ok(chunk) => ServerMessage::Icon(Icon::Png(chunk)),
+                    Err(_) => return,
+                }
+            }
+            (Some("ICON"), Some(name)) => ServerMessage::Icon(Icon::Name(name)),
+            (Some("BODY"), Some(line)) => ServerMessage::Body(line),
+            _ => return,
+        };
+        detail.add_to(details);
+    }
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(id) = self.id {
+            write!(f, "{id} ")?;
+        }
+        match &self.result {
+            Ok(arguments) => {
+                write!(f, "+{}", self.command)?;
+                for arg in arguments {
+                    write!(f, " {arg}")?;
+                }
+            }
+            Err(code) => write!(f, "-{} {code}", self.command)?,
+        }
+        match &self.trailing {
+            Some(trailing) => write!(f, " : {}", single_line(trailing)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &str {
+        match self {
+            ErrorCode::Parse => "PARSE",
+            ErrorCode::MissingTrailing => "MISSING_TRAILING",
+            ErrorCode::MissingArg => "MISSING_ARG",
+            ErrorCode::InvalidArg => "INVALID_ARG",
+            ErrorCode::InvalidMessage => "INVALID_MESSAGE",
+            ErrorCode::UnknownCmd => "UNKNOWN_CMD",
+            ErrorCode::NoLogin => "NO_LOGIN",
+            ErrorCode::AlreadyLoggedIn => "ALREADY_LOGGED_IN",
+            ErrorCode::AuthFailed => "AUTH_FAILED",
+            ErrorCode::Forbidden => "FORBIDDEN",
+            ErrorCode::NoTls => "NO_TLS",
+            ErrorCode::AlreadyTls => "ALREADY_TLS",
+            ErrorCode::TooLarge => "TOO_LARGE",
+            ErrorCode::NotFound => "NOT_FOUND",
+            ErrorCode::NoDb => "NO_DB",
+            ErrorCode::DbFail => "DB_FAIL",
+            ErrorCode::Other(code) => code,
+        }
+    }
+}
+
+impl From<&str> for ErrorCode {
+    fn from(code: &str) -> Self {
+        match code.to_uppercase().as_ref() {
+            "PARSE" => ErrorCode::Parse,
+            "MISSING_TRAILING" => ErrorCode::MissingTrailing,
+            "MISSING_ARG" => ErrorCode::MissingArg,
+            "INVALID_ARG" => ErrorCode::InvalidArg,
+            "INVALID_MESSAGE" => ErrorCode::InvalidMessage,
+            "UNKNOWN_CMD" => ErrorCode::UnknownCmd,
+            "NO_LOGIN" => ErrorCode::NoLogin,
+            "ALREADY_LOGGED_IN" => ErrorCode::AlreadyLoggedIn,
+            "AUTH_FAILED" => ErrorCode::AuthFailed,
+            "FORBIDDEN" => ErrorCode::Forbidden,
+            "NO_TLS" => ErrorCode::NoTls,
+            "ALREADY_TLS" => ErrorCode::AlreadyTls,
+            "TOO_LARGE" => ErrorCode::TooLarge,
+            "NOT_FOUND" => ErrorCode::NotFound,
+            "NO_DB" => ErrorCode::NoDb,
+            "DB_FAIL" => ErrorCode::DbFail,
+            _ => ErrorCode::Other(code.to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Display for InvalidArgument {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid {} {:?}", self.field, self.value)
+    }
+}
+
+impl std::error::Error for InvalidArgument {}
+
+impl ServerLine {
+    /// Parse a line received from the server, None if it is unknown or invalid
+    pub fn parse(line: &str) -> Option<Self> {
+        let (_, msg) = parser::line(line, false).ok()?;
+        match msg.sign? {
+            '$' => ServerMessage::parse(&msg).map(ServerLine::Message),
+            _ => Reply::parse(&msg).map(ServerLine::Reply),
+        }
+    }
+}
+
+/// Trailing text has to stay on its line
+fn single_line(text: &str) -> String {
+    text.replace(['\r', '\n'], " ")
+}
+
+#[test]
+fn round_trip() {
+    let commands = [
+        Command::Login { user: Some(String::from("rein@laptop")), password: Some(String::from("hunter2")) },
+        Command::Login { user: None, password: None },
+        Command::Title(String::from("Build finished")),
+        Command::Body { line: Some(String::from("all 12 tests passed")), reset: false },
+        Command::Body { line: None, reset: true },
+        Command::Icon { icon: Some(Icon::Png(vec![0x89, b'P', b'N', b'G'])), reset: true },
+        Command::Icon { icon: Some(Icon::Name(String::from("dialog-information"))), reset: false },
+        Command::Ephemeral(false),
+        Command::To(vec![String::from("rein"), String::from("bob@phone")]),
+        Command::Tags(vec![]),
+        Command::Urgency(Urgency::Critical),
+        Command::Action { key: String::from("open"), label: String::from("Open the log") },
+        Command::Callback(None),
+        Command::Invoke { id: 4, key: String::from("open") },
+        Command::Consume(true),
+        Command::History(Some(10)),
+        Command::Delete(Delete::Matching { user: Some(String::from("rein")), tag: None, older_than: Some(3600) }),
+    ];
+    for command in commands {
+        let (_, msg) = parser::line(&command.line(Some(7)), true).unwrap();
+        assert_eq!(msg.id, Some(7));
+        assert_eq!(Command::parse(&msg), Ok(command));
+    }
+
+    let mut details = NotificationDetails::new();
+    assert_eq!(ServerMessage::notification(&details), None);
+    assert_eq!(Reply::history(None, &details), None);
+    details.id = Some(12);
+    details.user = Some(String::from("rein"));
+    details.timestamp = Some(String::from("2025-01-01 12:00:00"));
+    details.title = Some(String::from("Build finished"));
+    details.body = Some(String::from("line one\nline two\n"));
+    details.tags = vec![String::from("ci")];
+    details.icon = Some(Icon::Png((0..200).collect()));
+    let messages = ServerMessage::notification(&details).unwrap();
+    assert_eq!(messages.iter().filter(|m| matches!(m, ServerMessage::Icon(_))).count(), 4);
+    for message in messages.into_iter().chain([
+        ServerMessage::Invoked { id: 3, key: String::from("open"), login: String::from("bob") },
+        ServerMessage::Notice(String::from("The server is shutting down.")),
+    ]) {
+        assert_eq!(ServerLine::parse(&message.to_string()), Some(ServerLine::Message(message)));
+    }
+
+    let replies = [
+        Reply::ok(Some(1), "SEND", vec![String::from("2"), String::from("12")]),
+        Reply::ok(None, "LOGIN", vec![]).with_trailing("Welcome rein"),
+        Reply::err(Some(3), "DELETE", ErrorCode::Forbidden).with_trailing("This notification belongs to another user."),
+        Reply::err(None, "ERR", ErrorCode::Other(String::from("SOMETHING_NEW"))),
+    ];
+    for reply in replies {
+        assert_eq!(ServerLine::parse(&reply.to_string()), Some(ServerLine::Reply(reply)));
+    }
+}
+
+#[test]
+fn invalid_commands() {
+    let parse = |line: &str| Command::parse(&parser::line(line, false).unwrap().1);
+    assert_eq!(parse("title").unwrap_err().result, Err(ErrorCode::MissingTrailing));
+    assert_eq!(parse("5 since soon").unwrap_err(), Reply::err(Some(5), "SINCE", ErrorCode::InvalidArg));
+    assert_eq!(parse("delete user").unwrap_err().result, Err(ErrorCode::InvalidArg));
+    assert_eq!(parse("frobnicate").unwrap_err().command, "ERR");
+    assert_eq!(parse("ephemeral").unwrap(), Command::Ephemeral(true));
+    assert_eq!(parse("callback:  ").unwrap(), Command::Callback(None));
+}
+
+#[test]
+fn compose_rejects_invalid_arguments() {
+    let mut details = NotificationDetails::new();
+    details.tags = vec![String::from("ci")];
+    details.recipients = vec![String::from("rein"), String::from("bob alice")];
+    let error = Command::compose(&details).unwrap_err();
+    assert_eq!(error, InvalidArgument { field: "recipient", value: String::from("bob alice") });
+    details.recipients.pop();
+    details.actions = vec![(String::from("open:now"), String::from("Open"))];
+    assert_eq!(Command::compose(&details).unwrap_err().field, "action");
+    details.actions.clear();
+    assert_eq!(Command::compose(&details).unwrap()[1], Command::Tags(vec![String::from("ci")]));
+}
@@ -0,0 +1,63 @@
+//! the line protocol spoken between clients and the server
+//!
+//! See `docs/spec.typst` for the specification.
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use crate::notifications::Icon;
+use crate::notifications::NotificationDetails;
+
+mod message;
+pub mod parser;
+
+pub use message::Command;
+pub use message::Delete;
+pub use message::ErrorCode;
+pub use message::InvalidArgument;
+pub use message::Reply;
+pub use message::ServerLine;
+pub use message::ServerMessage;
+
+/// Largest inline icon a client may send
+pub const MAX_ICON_SIZE: usize = 512 * 1024;
+
+/// Length of the base64 chunks inline icons are sent in
+const ICON_CHUNK: usize = 76;
+
+/// Create the `$NOTIFY_START` to `$NOTIFY_END` block relaying a notification,
+/// or `None` if it has no id yet
+pub fn notify_message(details: &NotificationDetails) -> Option<String> {
+    Some(ServerMessage::notification(details)?.iter().map(ServerMessage::line).collect())
+}
+
+/// Check if `arg` can be sent as a single argument, like a tag or recipient
+pub fn is_argument(arg: &str) -> bool {
+    !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || c == ':')
+}
+
+/// Parse a boolean argument, which is `true` when absent
+pub fn parse_bool(arg: Option<&String>) -> Option<bool> {
+    match arg.map(|a| a.to_lowercase()).as_deref() {
+        None | Some("on") | Some("true") => Some(true),
+        Some("off") | Some("false") => Some(false),
+        _ => None,
+    }
+}
+
+/// Split an icon into the parts sent on separate ICON lines
+pub fn icon_parts(icon: &Icon) -> Vec<Icon> {
+    match icon {
+        Icon::Name(name) => vec![Icon::Name(name.clone())],
+        Icon::Png(data) => data.chunks(ICON_CHUNK / 4 * 3).map(|chunk| Icon::Png(chunk.to_vec())).collect(),
+    }
+}
+
+/// Encode a chunk of an inline icon for a single line
+pub fn encode_icon_chunk(chunk: &[u8]) -> String {
+    BASE64.encode(chunk)
+}
+
+/// Decode a single base64 line of an inline icon
+pub fn decode_icon_chunk(chunk: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    BASE64.decode(chunk.trim())
+}
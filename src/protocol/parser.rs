@@ -28,7 +28,7 @@ fn arguments(input: &str) -> IResult<&str, Vec<&str>> {
 }
 
 fn trailing(input: &str) -> IResult<&str, &str> {
-    preceded(opt(space1), take_while(|c| c != '\r' || c != '\n')).parse(input)
+    preceded(opt(space1), take_while(|c| c != '\r' && c != '\n')).parse(input)
     //preceded(space1, is_not("\r\n")).parse(input)
 }
 